@@ -1,11 +1,24 @@
 use crate::types::*;
-use soroban_sdk::{contractevent, Address, BytesN, Env};
+use crate::storage_types::AssetPairKey;
+use soroban_sdk::{contractevent, Address, BytesN, Env, Symbol, Vec};
+
+/// Layout version of SettlementEvent, bumped whenever its fields change
+/// Version 2 added fee_recipient and schema_version itself; version 3 added
+/// fee_asset and fee_amount; version 4 added referrer; version 5 added both
+/// sides' vault balances after the trade
+pub const SETTLEMENT_EVENT_VERSION: u32 = 5;
+
+/// Layout version of DepositEvent and WithdrawEvent, bumped whenever either
+/// one's fields change. Version 2 added schema_version itself, after
+/// new_balance and WithdrawEvent's destination
+pub const VAULT_EVENT_VERSION: u32 = 2;
 
 // Event topics for better filtering and indexing
 // Topics are defined as string literals in the macro
 #[contractevent(topics = ["SETTLEMENT", "trade"])]
 #[derive(Clone, Debug)]
 pub struct SettlementEvent {
+    pub schema_version: u32,
     pub trade_id: BytesN<32>,
     pub buy_user: Address,
     pub sell_user: Address,
@@ -15,56 +28,645 @@ pub struct SettlementEvent {
     pub quote_amount: i128,
     pub execution_price: i128,
     pub execution_quantity: i128,
+    pub fee_base: i128,
+    pub fee_quote: i128,
+    pub maker_rebate: i128,
+    /// Separate fee token the taker paid fee_amount in, if any
+    pub fee_asset: Option<Address>,
+    pub fee_amount: i128,
+    /// Referrer the trade was attributed to, if any
+    pub referrer: Option<Address>,
+    /// Holder of the protocol fee balance the net fees were credited to
+    pub fee_recipient: Address,
+    pub taker_side: Side,
     pub timestamp: u64,
+    pub sequence: u64,
+    /// Vault balances after this trade, so each event is self-contained
+    pub buyer_base_after: i128,
+    pub buyer_quote_after: i128,
+    pub seller_base_after: i128,
+    pub seller_quote_after: i128,
+}
+
+/// One event summarizing the trades a settle_trades batch settled
+/// Totals add each trade's amounts as given, whichever asset was its base
+#[contractevent(topics = ["SETTLEMENT", "batch"])]
+#[derive(Clone, Debug)]
+pub struct BatchSettledEvent {
+    pub count: u32,
+    pub trade_ids: Vec<BytesN<32>>,
+    pub total_base: i128,
+    pub total_quote: i128,
+}
+
+/// A settlement rejected with a non-Success result. Beyond the trade ID it
+/// names no account: a balance or allowance shortfall gives the side that
+/// fell short (none for the protocol fee balance), the asset and the
+/// amount missing; other rejections leave them empty
+#[contractevent(topics = ["SETTLEMENT", "rejected"])]
+#[derive(Clone, Debug)]
+pub struct SettlementRejectedEvent {
+    pub trade_id: BytesN<32>,
+    pub result: SettlementResult,
+    pub side: Option<Side>,
+    pub asset: Option<Address>,
+    pub shortfall: i128,
 }
 
 #[contractevent(topics = ["DEPOSIT"])]
 #[derive(Clone, Debug)]
 pub struct DepositEvent {
+    pub schema_version: u32,
     pub user: Address,
     pub token: Address,
     pub amount: i128,
+    /// Vault balance after this operation, so each event is self-contained
+    pub new_balance: i128,
 }
 
 #[contractevent(topics = ["WITHDRAW"])]
 #[derive(Clone, Debug)]
 pub struct WithdrawEvent {
+    pub schema_version: u32,
     pub user: Address,
+    /// Address the tokens were sent to (the user, unless withdrawn elsewhere)
+    pub destination: Address,
     pub token: Address,
     pub amount: i128,
+    /// Vault balance after this operation, so each event is self-contained
+    pub new_balance: i128,
+}
+
+/// A change to the locked part of a user's vault balance: a lock (positive
+/// `change`), an unlock, or a settlement using locked funds (negative)
+#[contractevent(topics = ["VAULT", "lock"])]
+#[derive(Clone, Debug)]
+pub struct LockChangedEvent {
+    pub user: Address,
+    pub asset: Address,
+    pub change: i128,
+    /// Locked balance after this change
+    pub locked: i128,
+}
+
+// Configuration changes. Each CONFIG event carries the setting's previous
+// and new values and who made the change: `actor` for a single role (the
+// admin, or the fee manager for fee settings), `approvers` for operations
+// needing admin approval (the admin alone, or the admin signers that approved)
+
+#[contractevent(topics = ["CONFIG", "limits"])]
+#[derive(Clone, Debug)]
+pub struct InstructionLimitsEvent {
+    pub max_memo_bytes: u32,
+    pub max_proof_len: u32,
+    pub previous_max_memo_bytes: u32,
+    pub previous_max_proof_len: u32,
+    pub actor: Address,
+}
+
+/// One of the pair's assets replaced by set_asset_a (`position` 0) or
+/// set_asset_b (`position` 1)
+#[contractevent(topics = ["CONFIG", "asset"])]
+#[derive(Clone, Debug)]
+pub struct AssetChangedEvent {
+    pub position: u32,
+    pub previous: Address,
+    pub asset: Address,
+    pub actor: Address,
+}
+
+#[contractevent(topics = ["CONFIG", "asset_added"])]
+#[derive(Clone, Debug)]
+pub struct AssetAddedEvent {
+    pub asset: Address,
+    pub actor: Address,
+}
+
+#[contractevent(topics = ["CONFIG", "engine"])]
+#[derive(Clone, Debug)]
+pub struct EngineChangedEvent {
+    pub previous: Option<Address>,
+    pub engine: Address,
+    pub approvers: Vec<Address>,
+}
+
+/// A change to who controls admin operations: the M-of-N signer set and
+/// its threshold (0 with no signers leaves them to the admin alone)
+#[contractevent(topics = ["CONFIG", "signers"])]
+#[derive(Clone, Debug)]
+pub struct AdminSignersChangedEvent {
+    pub previous_signers: Vec<Address>,
+    pub previous_threshold: u32,
+    pub signers: Vec<Address>,
+    pub threshold: u32,
+    pub approvers: Vec<Address>,
+}
+
+#[contractevent(topics = ["CONFIG", "upgrade"])]
+#[derive(Clone, Debug)]
+pub struct UpgradedEvent {
+    pub wasm_hash: BytesN<32>,
+    pub approvers: Vec<Address>,
+}
+
+#[contractevent(topics = ["CONFIG", "paused"])]
+#[derive(Clone, Debug)]
+pub struct PausedChangedEvent {
+    pub asset: Address,
+    pub previous: bool,
+    pub paused: bool,
+    pub approvers: Vec<Address>,
+}
+
+#[contractevent(topics = ["CONFIG", "settlement_mode"])]
+#[derive(Clone, Debug)]
+pub struct SettlementModeChangedEvent {
+    pub previous: SettlementMode,
+    pub mode: SettlementMode,
+    pub actor: Address,
+}
+
+#[contractevent(topics = ["CONFIG", "auth_mode"])]
+#[derive(Clone, Debug)]
+pub struct AuthModeChangedEvent {
+    pub previous: AuthMode,
+    pub mode: AuthMode,
+    pub actor: Address,
+}
+
+/// A change to any venue-wide fee setting, with every setting before and
+/// after it
+#[contractevent(topics = ["CONFIG", "fee_params"])]
+#[derive(Clone, Debug)]
+pub struct FeeParamsChangedEvent {
+    pub previous: FeeParams,
+    pub params: FeeParams,
+    pub actor: Address,
+}
+
+/// A pair's fee override set or (with `rates` none) removed; the pair is in
+/// its canonical orientation
+#[contractevent(topics = ["CONFIG", "pair_fee"])]
+#[derive(Clone, Debug)]
+pub struct PairFeeChangedEvent {
+    pub base: Address,
+    pub quote: Address,
+    pub previous: Option<FeeRates>,
+    pub rates: Option<FeeRates>,
+    pub actor: Address,
 }
 
-pub fn emit_settlement_event(env: &Env, instruction: &SettlementInstruction) {
+#[contractevent(topics = ["CONFIG", "fee_tiers"])]
+#[derive(Clone, Debug)]
+pub struct FeeTiersChangedEvent {
+    pub previous: Vec<(i128, u32)>,
+    pub tiers: Vec<(i128, u32)>,
+    pub actor: Address,
+}
+
+#[contractevent(topics = ["CONFIG", "keeper_reward"])]
+#[derive(Clone, Debug)]
+pub struct KeeperRewardChangedEvent {
+    pub asset: Address,
+    pub previous: i128,
+    pub amount: i128,
+    pub actor: Address,
+}
+
+/// A change to one of the addresses holding a role: `fee_manager`,
+/// `fee_recipient` or `insurance_admin`. `previous` is none while the role
+/// fell back to the admin
+#[contractevent(topics = ["CONFIG", "role"])]
+#[derive(Clone, Debug)]
+pub struct RoleChangedEvent {
+    #[topic]
+    pub role: Symbol,
+    pub previous: Option<Address>,
+    pub holder: Address,
+    pub approvers: Vec<Address>,
+}
+
+/// An asset-wide deposit cap (`user` none) or one user's cap changed;
+/// none means unlimited, or for a user that the asset-wide cap applies
+#[contractevent(topics = ["CONFIG", "deposit_cap"])]
+#[derive(Clone, Debug)]
+pub struct DepositCapChangedEvent {
+    pub asset: Address,
+    pub user: Option<Address>,
+    pub previous: Option<i128>,
+    pub cap: Option<i128>,
+    pub actor: Address,
+}
+
+/// A change to one of the admin's numeric or on/off settings:
+/// `volume_epoch` (seconds), `vwap_min_volume`, `batch_summary_only` and
+/// `auto_pause` (1 on, 0 off)
+#[contractevent(topics = ["CONFIG", "setting"])]
+#[derive(Clone, Debug)]
+pub struct SettingChangedEvent {
+    #[topic]
+    pub setting: Symbol,
+    pub previous: i128,
+    pub value: i128,
+    pub actor: Address,
+}
+
+#[contractevent(topics = ["CONFIG", "import_closed"])]
+#[derive(Clone, Debug)]
+pub struct ImportClosedEvent {
+    pub actor: Address,
+}
+
+#[contractevent(topics = ["FEES", "claimed"])]
+#[derive(Clone, Debug)]
+pub struct FeesClaimedEvent {
+    pub asset: Address,
+    pub recipient: Address,
+    pub amount: i128,
+}
+
+#[contractevent(topics = ["FEES", "insurance"])]
+#[derive(Clone, Debug)]
+pub struct InsuranceClaimedEvent {
+    pub asset: Address,
+    pub recipient: Address,
+    pub amount: i128,
+}
+
+#[contractevent(topics = ["INSURANCE", "payout"])]
+#[derive(Clone, Debug)]
+pub struct InsurancePayoutEvent {
+    pub asset: Address,
+    pub recipient: Address,
+    pub amount: i128,
+    /// Off-chain incident or claim identifier the payout compensates
+    pub reason_code: u32,
+}
+
+/// Outcome of a reconcile call, including any automatic pause it triggered
+#[contractevent(topics = ["VAULT", "reconciled"])]
+#[derive(Clone, Debug)]
+pub struct ReconciledEvent {
+    pub asset: Address,
+    pub token_balance: i128,
+    pub internal_total: i128,
+    pub surplus: i128,
+    pub paused: bool,
+}
+
+#[contractevent(topics = ["FEES", "referral"])]
+#[derive(Clone, Debug)]
+pub struct ReferralClaimedEvent {
+    pub asset: Address,
+    pub referrer: Address,
+    pub amount: i128,
+}
+
+pub fn emit_settlement_event(env: &Env, settled: &SettledTrade, fee_recipient: &Address) {
+    let record = &settled.record;
     // Emit comprehensive settlement event
     SettlementEvent {
+        schema_version: SETTLEMENT_EVENT_VERSION,
+        trade_id: record.trade_id.clone(),
+        buy_user: record.buy_user.clone(),
+        sell_user: record.sell_user.clone(),
+        base_asset: record.base_asset.clone(),
+        quote_asset: record.quote_asset.clone(),
+        base_amount: record.base_amount,
+        quote_amount: record.quote_amount,
+        execution_price: record.execution_price,
+        execution_quantity: record.execution_quantity,
+        fee_base: record.fee_base,
+        fee_quote: record.fee_quote,
+        maker_rebate: record.maker_rebate,
+        fee_asset: record.fee_asset.clone(),
+        fee_amount: record.fee_amount,
+        referrer: record.referrer.clone(),
+        fee_recipient: fee_recipient.clone(),
+        taker_side: record.taker_side,
+        timestamp: record.timestamp,
+        sequence: record.sequence,
+        buyer_base_after: settled.buyer_base_balance,
+        buyer_quote_after: settled.buyer_quote_balance,
+        seller_base_after: settled.seller_base_balance,
+        seller_quote_after: settled.seller_quote_balance,
+    }
+    .publish(env);
+}
+
+pub fn emit_settlement_rejected_event(env: &Env, instruction: &SettlementInstruction, outcome: &SettlementOutcome) {
+    let (side, asset, shortfall) = match &outcome.detail {
+        OutcomeDetail::Shortfall(shortfall) => {
+            let side = if shortfall.user == instruction.buy_user {
+                Some(Side::Buy)
+            } else if shortfall.user == instruction.sell_user {
+                Some(Side::Sell)
+            } else {
+                None
+            };
+            (side, Some(shortfall.asset.clone()), shortfall.required - shortfall.available)
+        }
+        _ => (None, None, 0),
+    };
+    SettlementRejectedEvent {
         trade_id: instruction.trade_id.clone(),
-        buy_user: instruction.buy_user.clone(),
-        sell_user: instruction.sell_user.clone(),
-        base_asset: instruction.base_asset.clone(),
-        quote_asset: instruction.quote_asset.clone(),
-        base_amount: instruction.base_amount,
-        quote_amount: instruction.quote_amount,
-        execution_price: 0, // Placeholder - no matching proof
-        execution_quantity: 0, // Placeholder - no matching proof
-        timestamp: instruction.timestamp,
+        result: outcome.result.clone(),
+        side,
+        asset,
+        shortfall,
     }
     .publish(env);
 }
 
-pub fn emit_deposit_event(env: &Env, user: &Address, token: &Address, amount: i128) {
+pub fn emit_batch_settled_event(env: &Env, trade_ids: &Vec<BytesN<32>>, total_base: i128, total_quote: i128) {
+    BatchSettledEvent {
+        count: trade_ids.len(),
+        trade_ids: trade_ids.clone(),
+        total_base,
+        total_quote,
+    }
+    .publish(env);
+}
+
+pub fn emit_deposit_event(
+    env: &Env,
+    user: &Address,
+    token: &Address,
+    amount: i128,
+    new_balance: i128,
+) {
     DepositEvent {
+        schema_version: VAULT_EVENT_VERSION,
         user: user.clone(),
         token: token.clone(),
         amount,
+        new_balance,
     }
     .publish(env);
 }
 
-pub fn emit_withdraw_event(env: &Env, user: &Address, token: &Address, amount: i128) {
+pub fn emit_withdraw_event(
+    env: &Env,
+    user: &Address,
+    destination: &Address,
+    token: &Address,
+    amount: i128,
+    new_balance: i128,
+) {
     WithdrawEvent {
+        schema_version: VAULT_EVENT_VERSION,
         user: user.clone(),
+        destination: destination.clone(),
         token: token.clone(),
         amount,
+        new_balance,
+    }
+    .publish(env);
+}
+
+pub fn emit_lock_changed_event(env: &Env, user: &Address, asset: &Address, change: i128, locked: i128) {
+    LockChangedEvent {
+        user: user.clone(),
+        asset: asset.clone(),
+        change,
+        locked,
+    }
+    .publish(env);
+}
+
+pub fn emit_instruction_limits_event(env: &Env, previous: &InstructionLimits, limits: &InstructionLimits, actor: &Address) {
+    InstructionLimitsEvent {
+        max_memo_bytes: limits.max_memo_bytes,
+        max_proof_len: limits.max_proof_len,
+        previous_max_memo_bytes: previous.max_memo_bytes,
+        previous_max_proof_len: previous.max_proof_len,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_asset_changed_event(env: &Env, position: u32, previous: &Address, asset: &Address, actor: &Address) {
+    AssetChangedEvent {
+        position,
+        previous: previous.clone(),
+        asset: asset.clone(),
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_asset_added_event(env: &Env, asset: &Address, actor: &Address) {
+    AssetAddedEvent {
+        asset: asset.clone(),
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_engine_changed_event(env: &Env, previous: Option<Address>, engine: &Address, approvers: Vec<Address>) {
+    EngineChangedEvent {
+        previous,
+        engine: engine.clone(),
+        approvers,
+    }
+    .publish(env);
+}
+
+pub fn emit_admin_signers_changed_event(
+    env: &Env,
+    (previous_signers, previous_threshold): (Vec<Address>, u32),
+    signers: &Vec<Address>,
+    threshold: u32,
+    approvers: Vec<Address>,
+) {
+    AdminSignersChangedEvent {
+        previous_signers,
+        previous_threshold,
+        signers: signers.clone(),
+        threshold,
+        approvers,
+    }
+    .publish(env);
+}
+
+pub fn emit_upgraded_event(env: &Env, wasm_hash: &BytesN<32>, approvers: Vec<Address>) {
+    UpgradedEvent {
+        wasm_hash: wasm_hash.clone(),
+        approvers,
+    }
+    .publish(env);
+}
+
+pub fn emit_paused_changed_event(env: &Env, asset: &Address, previous: bool, paused: bool, approvers: Vec<Address>) {
+    PausedChangedEvent {
+        asset: asset.clone(),
+        previous,
+        paused,
+        approvers,
+    }
+    .publish(env);
+}
+
+pub fn emit_settlement_mode_changed_event(env: &Env, previous: SettlementMode, mode: SettlementMode, actor: &Address) {
+    SettlementModeChangedEvent {
+        previous,
+        mode,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_auth_mode_changed_event(env: &Env, previous: AuthMode, mode: AuthMode, actor: &Address) {
+    AuthModeChangedEvent {
+        previous,
+        mode,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_fee_params_changed_event(env: &Env, previous: FeeParams, params: FeeParams, actor: &Address) {
+    FeeParamsChangedEvent {
+        previous,
+        params,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_pair_fee_changed_event(
+    env: &Env,
+    pair: &AssetPairKey,
+    previous: Option<FeeRates>,
+    rates: Option<FeeRates>,
+    actor: &Address,
+) {
+    PairFeeChangedEvent {
+        base: pair.base.clone(),
+        quote: pair.quote.clone(),
+        previous,
+        rates,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_fee_tiers_changed_event(env: &Env, previous: Vec<(i128, u32)>, tiers: &Vec<(i128, u32)>, actor: &Address) {
+    FeeTiersChangedEvent {
+        previous,
+        tiers: tiers.clone(),
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_keeper_reward_changed_event(env: &Env, asset: &Address, previous: i128, amount: i128, actor: &Address) {
+    KeeperRewardChangedEvent {
+        asset: asset.clone(),
+        previous,
+        amount,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_role_changed_event(
+    env: &Env,
+    role: &str,
+    previous: Option<Address>,
+    holder: &Address,
+    approvers: Vec<Address>,
+) {
+    RoleChangedEvent {
+        role: Symbol::new(env, role),
+        previous,
+        holder: holder.clone(),
+        approvers,
+    }
+    .publish(env);
+}
+
+pub fn emit_deposit_cap_changed_event(
+    env: &Env,
+    asset: &Address,
+    user: Option<Address>,
+    (previous, cap): (Option<i128>, Option<i128>),
+    actor: &Address,
+) {
+    DepositCapChangedEvent {
+        asset: asset.clone(),
+        user,
+        previous,
+        cap,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_setting_changed_event(env: &Env, setting: &str, previous: i128, value: i128, actor: &Address) {
+    SettingChangedEvent {
+        setting: Symbol::new(env, setting),
+        previous,
+        value,
+        actor: actor.clone(),
+    }
+    .publish(env);
+}
+
+pub fn emit_import_closed_event(env: &Env, actor: &Address) {
+    ImportClosedEvent { actor: actor.clone() }.publish(env);
+}
+
+pub fn emit_fees_claimed_event(env: &Env, asset: &Address, recipient: &Address, amount: i128) {
+    FeesClaimedEvent {
+        asset: asset.clone(),
+        recipient: recipient.clone(),
+        amount,
+    }
+    .publish(env);
+}
+
+pub fn emit_insurance_claimed_event(env: &Env, asset: &Address, recipient: &Address, amount: i128) {
+    InsuranceClaimedEvent {
+        asset: asset.clone(),
+        recipient: recipient.clone(),
+        amount,
+    }
+    .publish(env);
+}
+
+pub fn emit_insurance_payout_event(
+    env: &Env,
+    asset: &Address,
+    recipient: &Address,
+    amount: i128,
+    reason_code: u32,
+) {
+    InsurancePayoutEvent {
+        asset: asset.clone(),
+        recipient: recipient.clone(),
+        amount,
+        reason_code,
+    }
+    .publish(env);
+}
+
+pub fn emit_reconciled_event(env: &Env, report: &ReconciliationReport) {
+    ReconciledEvent {
+        asset: report.asset.clone(),
+        token_balance: report.token_balance,
+        internal_total: report.internal_total,
+        surplus: report.surplus,
+        paused: report.paused,
+    }
+    .publish(env);
+}
+
+pub fn emit_referral_claimed_event(env: &Env, asset: &Address, referrer: &Address, amount: i128) {
+    ReferralClaimedEvent {
+        asset: asset.clone(),
+        referrer: referrer.clone(),
+        amount,
     }
     .publish(env);
 }
@@ -0,0 +1,403 @@
+use crate::storage;
+use crate::types::*;
+use soroban_sdk::{Address, Env, Vec};
+
+/// Basis points per whole (100%)
+pub const BPS_DENOMINATOR: i128 = 10_000;
+
+/// Highest configurable fee rate (10%)
+pub const MAX_FEE_BPS: u32 = 1_000;
+
+/// Require auth from the fee manager, or the admin if no manager is set,
+/// and return whichever authorized the call
+pub fn require_fee_authority(env: &Env) -> Address {
+    let authority = storage::get_fee_manager(env).unwrap_or_else(|| storage::get_admin(env));
+    authority.require_auth();
+    authority
+}
+
+/// Current venue-wide fee settings
+pub fn fee_params(env: &Env) -> FeeParams {
+    let rates = storage::get_fee_rates(env);
+    let split = storage::get_fee_split(env);
+    FeeParams {
+        fee_mode: storage::get_fee_mode(env),
+        rounding_mode: storage::get_rounding_mode(env),
+        maker_fee_bps: rates.as_ref().map(|rates| rates.maker_bps),
+        taker_fee_bps: rates.map(|rates| rates.taker_bps),
+        insurance_bps: split.insurance_bps,
+        referrer_bps: split.referrer_bps,
+        referral_bps: storage::get_referral_bps(env),
+        maker_rebate_bps: storage::get_maker_rebate_bps(env),
+        max_fee_bps: storage::get_max_fee_bps(env),
+        max_fee_ratio_bps: storage::get_max_fee_ratio_bps(env),
+    }
+}
+
+/// Fee on `amount` at `bps`, rounded down (floor)
+/// Split as (amount / 10_000) * bps + (amount % 10_000) * bps / 10_000, which
+/// equals floor(amount * bps / 10_000) without overflowing for any amount
+pub fn fee_for(amount: i128, bps: u32) -> i128 {
+    let bps = bps as i128;
+    (amount / BPS_DENOMINATOR) * bps + (amount % BPS_DENOMINATOR) * bps / BPS_DENOMINATOR
+}
+
+/// Fee on a non-negative `amount` at `bps`, rounded as `mode` says
+/// The fractional part left by fee_for is (amount % 10_000) * bps % 10_000
+/// ten-thousandths of a unit
+pub fn rounded_fee(amount: i128, bps: u32, mode: RoundingMode) -> i128 {
+    let floor = fee_for(amount, bps);
+    let remainder = (amount % BPS_DENOMINATOR) * bps as i128 % BPS_DENOMINATOR;
+    let round_up = match mode {
+        RoundingMode::Floor => false,
+        RoundingMode::Ceil => remainder != 0,
+        RoundingMode::HalfUp => remainder * 2 >= BPS_DENOMINATOR,
+    };
+    floor + round_up as i128
+}
+
+/// Largest fee allowed on `amount` at `bps`, rounded up so that fees an
+/// engine computed with ceiling rounding still pass
+pub fn fee_allowance(amount: i128, bps: u32) -> i128 {
+    rounded_fee(amount, bps, RoundingMode::Ceil)
+}
+
+/// On-chain fee rates for a pair: its override if one is set, otherwise the
+/// global rates (`None` while fees are instruction-supplied)
+pub fn effective_rates(env: &Env, base_asset: &Address, quote_asset: &Address) -> Option<FeeRates> {
+    let pair = storage::pair_key(env, base_asset, quote_asset);
+    storage::get_pair_fee_rates(env, &pair).or_else(|| storage::get_fee_rates(env))
+}
+
+/// Whether instruction-supplied fees exceed the configured cap on either leg
+/// On-chain rates are bounded when set, so only instruction fees are checked
+pub fn exceeds_fee_cap(env: &Env, instruction: &SettlementInstruction) -> bool {
+    if instruction.fee_asset.is_some() || effective_rates(env, &instruction.base_asset, &instruction.quote_asset).is_some() {
+        return false;
+    }
+    match storage::get_max_fee_bps(env) {
+        Some(cap) => {
+            instruction.fee_base > fee_allowance(instruction.base_amount, cap)
+                || instruction.fee_quote > fee_allowance(instruction.quote_amount, cap)
+        }
+        None => false,
+    }
+}
+
+/// Highest number of volume fee tiers
+pub const MAX_FEE_TIERS: u32 = 10;
+
+/// On-chain rates (buyer_bps, seller_bps) for a trade, or `None` while fees
+/// are instruction-supplied. Each side pays the taker or maker rate
+/// according to `taker_side`
+fn side_rates(env: &Env, base_asset: &Address, quote_asset: &Address, taker_side: Side) -> Option<(u32, u32)> {
+    effective_rates(env, base_asset, quote_asset).map(|rates| match taker_side {
+        Side::Buy => (rates.taker_bps, rates.maker_bps),
+        Side::Sell => (rates.maker_bps, rates.taker_bps),
+    })
+}
+
+/// The fee tier a user's epoch volume reaches, if any: the entry with the
+/// highest minimum volume not above it
+pub fn user_tier(env: &Env, tiers: &Vec<(i128, u32)>, user: &Address) -> Option<(i128, u32)> {
+    if tiers.is_empty() {
+        return None;
+    }
+    let volume = storage::get_user_volume(env, user);
+    tiers.iter().filter(|(min_volume, _)| *min_volume <= volume).last()
+}
+
+/// A side's rate after its volume tier, which can only lower it
+fn tiered_bps(env: &Env, tiers: &Vec<(i128, u32)>, user: &Address, bps: u32) -> u32 {
+    match user_tier(env, tiers, user) {
+        Some((_, tier_bps)) => bps.min(tier_bps),
+        None => bps,
+    }
+}
+
+/// Fees charged on one settlement, split by who pays them and in which asset
+/// The maker's fee is negative when the protocol pays it a rebate
+pub struct TradeFees {
+    /// Seller's fee in the base asset (per-leg mode)
+    pub seller_base: i128,
+    /// Seller's fee in the quote asset, taken out of its proceeds (quote-only mode)
+    pub seller_quote: i128,
+    /// Buyer's fee in the quote asset
+    pub buyer_quote: i128,
+}
+
+impl TradeFees {
+    /// Split (seller, buyer) fees according to the fee mode
+    fn new(mode: FeeMode, seller_fee: i128, buyer_fee: i128) -> Self {
+        match mode {
+            FeeMode::PerLeg => TradeFees { seller_base: seller_fee, seller_quote: 0, buyer_quote: buyer_fee },
+            FeeMode::QuoteOnly => TradeFees { seller_base: 0, seller_quote: seller_fee, buyer_quote: buyer_fee },
+        }
+    }
+
+    /// Total fee charged in the base asset
+    pub fn fee_base(&self) -> i128 {
+        self.seller_base
+    }
+
+    /// Total fee charged in the quote asset
+    pub fn fee_quote(&self) -> i128 {
+        self.buyer_quote + self.seller_quote
+    }
+
+    /// Quote taken from the buyer and base taken from the seller: each
+    /// side's amount plus any fee it pays in that asset
+    pub fn required_amounts(&self, instruction: &SettlementInstruction) -> (i128, i128) {
+        (
+            instruction.quote_amount + self.buyer_quote.max(0),
+            instruction.base_amount + self.seller_base.max(0),
+        )
+    }
+
+    /// Rebates (base, quote) owed to the maker out of the protocol fee
+    /// balance, from a negative maker fee
+    pub fn protocol_rebates(&self) -> (i128, i128) {
+        (-self.seller_base.min(0), -self.buyer_quote.min(0))
+    }
+}
+
+/// The seller's fee leg: its base amount in per-leg mode, or the quote
+/// amount it receives in quote-only mode
+fn seller_fee_leg(mode: FeeMode, base_amount: i128, quote_amount: i128) -> i128 {
+    match mode {
+        FeeMode::PerLeg => base_amount,
+        FeeMode::QuoteOnly => quote_amount,
+    }
+}
+
+/// On-chain fees for a trade between `buyer` and `seller`, each side's rate
+/// lowered by its volume tier, or `None` while fees are instruction-supplied
+fn tiered_fees(
+    env: &Env,
+    base_asset: &Address,
+    quote_asset: &Address,
+    (base_amount, quote_amount): (i128, i128),
+    taker_side: Side,
+    (buyer, seller): (&Address, &Address),
+    mode: FeeMode,
+) -> Option<TradeFees> {
+    side_rates(env, base_asset, quote_asset, taker_side).map(|(buyer_bps, seller_bps)| {
+        let tiers = storage::get_fee_tiers(env);
+        let buyer_bps = tiered_bps(env, &tiers, buyer, buyer_bps);
+        let seller_bps = tiered_bps(env, &tiers, seller, seller_bps);
+        let rounding = storage::get_rounding_mode(env);
+        TradeFees::new(
+            mode,
+            rounded_fee(seller_fee_leg(mode, base_amount, quote_amount), seller_bps, rounding),
+            rounded_fee(quote_amount, buyer_bps, rounding),
+        )
+    })
+}
+
+/// On-chain fees (base, quote) for a trade between `buyer` and `seller`,
+/// after their volume tiers, or `None` while fees are instruction-supplied.
+/// In per-leg mode the seller pays the base fee and the buyer the quote fee;
+/// in quote-only mode both sides' fees are charged in the quote asset
+pub fn computed_fees(
+    env: &Env,
+    parties: (&Address, &Address),
+    base_asset: &Address,
+    quote_asset: &Address,
+    base_amount: i128,
+    quote_amount: i128,
+    taker_side: Side,
+) -> Option<(i128, i128)> {
+    let mode = storage::get_fee_mode(env);
+    tiered_fees(env, base_asset, quote_asset, (base_amount, quote_amount), taker_side, parties, mode)
+        .map(|fees| (fees.fee_base(), fees.fee_quote()))
+}
+
+/// Whether a quote-only venue was sent an instruction-supplied base fee
+/// On-chain rates never charge one, so only instruction fees are checked
+pub fn has_forbidden_base_fee(env: &Env, instruction: &SettlementInstruction) -> bool {
+    instruction.fee_asset.is_none()
+        && instruction.fee_base != 0
+        && storage::get_fee_mode(env) == FeeMode::QuoteOnly
+        && effective_rates(env, &instruction.base_asset, &instruction.quote_asset).is_none()
+}
+
+/// Whether instruction-supplied fees charge the taker a negative fee
+/// Only the maker may be paid a rebate, and on-chain rates never pay one,
+/// so only instruction fees are checked
+pub fn has_negative_taker_fee(env: &Env, instruction: &SettlementInstruction) -> bool {
+    let taker_fee = match instruction.taker_side {
+        Side::Buy => instruction.fee_quote,
+        Side::Sell => instruction.fee_base,
+    };
+    instruction.fee_asset.is_none()
+        && taker_fee < 0
+        && side_rates(env, &instruction.base_asset, &instruction.quote_asset, instruction.taker_side).is_none()
+}
+
+/// Fees charged for a settlement
+/// With configured rates the fees are computed on-chain, lowered by each
+/// side's volume tier and rounded by the rounding mode, and the
+/// instruction's fee fields are ignored;
+/// otherwise the instruction-supplied fees apply, with fee_quote paid by the
+/// buyer in either mode. Instruction fees may be negative on the maker's side
+/// only, as a rebate paid by the protocol; settle rejects a negative taker
+/// fee with has_negative_taker_fee first. An instruction with a fee_asset
+/// pays no base or quote fee at all
+pub fn settlement_fees(env: &Env, instruction: &SettlementInstruction, mode: FeeMode) -> TradeFees {
+    if instruction.fee_asset.is_some() {
+        return TradeFees::new(mode, 0, 0);
+    }
+    tiered_fees(
+        env,
+        &instruction.base_asset,
+        &instruction.quote_asset,
+        (instruction.base_amount, instruction.quote_amount),
+        instruction.taker_side,
+        (&instruction.buy_user, &instruction.sell_user),
+        mode,
+    )
+    .unwrap_or_else(|| TradeFees::new(mode, instruction.fee_base, instruction.fee_quote))
+}
+
+/// Whether a settlement's total fee exceeds the admin's cap relative to its
+/// notional, whatever computed it. The base fee is valued in the quote asset
+/// at the trade's own price, so both legs compare against the quote amount.
+/// Rebates (negative fees) are ignored, and products too large for i128
+/// saturate and so count as excessive. A fee in a separate fee_asset has no
+/// price against the pair and is not counted
+pub fn exceeds_fee_ratio(env: &Env, instruction: &SettlementInstruction, fees: &TradeFees) -> bool {
+    let ratio_bps = match storage::get_max_fee_ratio_bps(env) {
+        Some(ratio_bps) => ratio_bps,
+        None => return false,
+    };
+    let base_fee_value = fees.fee_base().max(0).saturating_mul(instruction.quote_amount) / instruction.base_amount;
+    let total_fee = fees.fee_quote().max(0).saturating_add(base_fee_value);
+    total_fee.saturating_mul(BPS_DENOMINATOR) > instruction.quote_amount.saturating_mul(ratio_bps as i128)
+}
+
+/// Rebate paid to the maker, in the asset of the taker's fee: the rebate
+/// rate on the maker's leg in that asset, never more than the taker fee
+/// actually charged, so the protocol's net fee cannot go negative
+pub fn maker_rebate(env: &Env, instruction: &SettlementInstruction, fees: &TradeFees, mode: FeeMode) -> i128 {
+    let bps = storage::get_maker_rebate_bps(env);
+    if bps == 0 {
+        return 0;
+    }
+    let (taker_fee, maker_amount) = match instruction.taker_side {
+        Side::Buy => (fees.buyer_quote, instruction.quote_amount),
+        Side::Sell => (
+            fees.seller_base + fees.seller_quote,
+            seller_fee_leg(mode, instruction.base_amount, instruction.quote_amount),
+        ),
+    };
+    fee_for(maker_amount, bps).min(taker_fee)
+}
+
+/// Count a settlement once towards each user's volume stats and tier
+/// volume, in the pair's canonical quote asset. Tier volume is only
+/// tracked while tiers are configured
+pub fn record_volume(env: &Env, instruction: &SettlementInstruction) {
+    let pair = storage::pair_key(env, &instruction.base_asset, &instruction.quote_asset);
+    let (_, notional) = storage::canonical_amounts(&pair, instruction);
+    storage::add_user_volume_stats(env, &instruction.buy_user, notional);
+    storage::add_user_volume_stats(env, &instruction.sell_user, notional);
+    if storage::get_fee_tiers(env).is_empty() {
+        return;
+    }
+    storage::add_user_volume(env, &instruction.buy_user, notional);
+    storage::add_user_volume(env, &instruction.sell_user, notional);
+}
+
+/// Credit a keeper's vault balance with the reward for `items` settled
+/// items, in each asset that has a keeper reward, out of the protocol fee
+/// balance. An asset whose fee balance cannot cover the whole reward is
+/// skipped rather than failing the batch, so user funds are never touched
+pub fn pay_keeper_reward(env: &Env, keeper: &Address, items: u32) {
+    if items == 0 {
+        return;
+    }
+    for asset in storage::get_supported_assets(env) {
+        let per_item = storage::get_keeper_reward(env, &asset);
+        if per_item == 0 {
+            continue;
+        }
+        let reward = per_item.checked_mul(items as i128).expect("Keeper reward overflow");
+        if storage::get_fee_balance(env, &asset) < reward {
+            continue;
+        }
+        storage::debit_fee(env, &asset, reward);
+        storage::add_balance(env, keeper, &asset, reward);
+        storage::record_net_flows(env, keeper, &[(&asset, reward)]);
+        storage::record_activity(env, keeper, ActivityKind::KeeperReward, None, &[(&asset, reward)]);
+    }
+}
+
+/// Largest combined insurance, referrer and referral rebate share (100% of the fee)
+pub const MAX_FEE_SPLIT_BPS: u32 = 10_000;
+
+/// Whether the insurance and referrer shares plus the referral rebate fit in
+/// one fee, so the rebate always fits in the protocol's share of it
+pub fn fee_shares_fit(split: &FeeSplit, referral_bps: u32) -> bool {
+    split
+        .insurance_bps
+        .saturating_add(split.referrer_bps)
+        .saturating_add(referral_bps)
+        <= MAX_FEE_SPLIT_BPS
+}
+
+/// Credit a trade's referrer `referral_bps` of each quote fee it collected,
+/// out of the protocol fee balance those fees were just credited to. No
+/// rebate is paid when the referrer is one of the trade's own parties
+pub fn pay_referral_rebate(env: &Env, instruction: &SettlementInstruction, quote_fees: &[i128]) {
+    let Some(referrer) = instruction.referrer.as_ref() else {
+        return;
+    };
+    if referrer == &instruction.buy_user || referrer == &instruction.sell_user {
+        return;
+    }
+    let referral_bps = storage::get_referral_bps(env);
+    let rebate: i128 = quote_fees
+        .iter()
+        .map(|fee| fee_for((*fee).max(0), referral_bps))
+        .sum();
+    if rebate > 0 {
+        let quote = &instruction.quote_asset;
+        storage::debit_fee(env, quote, rebate);
+        storage::add_balance(env, referrer, quote, rebate);
+        storage::record_net_flows(env, referrer, &[(quote, rebate)]);
+        let trade_id = Some(&instruction.trade_id);
+        storage::record_activity(env, referrer, ActivityKind::ReferralRebate, trade_id, &[(quote, rebate)]);
+    }
+}
+
+/// Referrer owed a share of the fee a user pays, whether as maker or taker:
+/// theirs, if they registered one and the referrer share is nonzero
+pub fn referrer_of(env: &Env, user: &Address, split: &FeeSplit) -> Option<Address> {
+    if split.referrer_bps == 0 {
+        return None;
+    }
+    storage::get_referrer(env, user)
+}
+
+/// Split a settlement fee between the insurance fund, the referrer and the
+/// protocol fee balance. Both shares round down, so the protocol receives the
+/// remainder and the three parts always add up to the whole fee
+pub fn collect_fee(env: &Env, asset: &Address, fee: i128, split: &FeeSplit, referrer: Option<&Address>) {
+    storage::add_fees_collected(env, asset, fee);
+
+    let insurance = fee_for(fee, split.insurance_bps);
+    if insurance > 0 {
+        storage::adjust_insurance_balance(env, asset, insurance);
+    }
+    let referral = match referrer {
+        Some(referrer) => {
+            let referral = fee_for(fee, split.referrer_bps);
+            if referral > 0 {
+                storage::adjust_referral_balance(env, referrer, asset, referral);
+            }
+            referral
+        }
+        None => 0,
+    };
+    storage::credit_fee(env, asset, fee - insurance - referral);
+}
@@ -1,19 +1,105 @@
 #![no_std]
-use soroban_sdk::{contract, contractimpl, log, Address, BytesN, Env, Vec};
+use soroban_sdk::{contract, contractimpl, log, panic_with_error, xdr::ToXdr, Address, BytesN, Env, Map, Vec};
 
 mod events;
+mod fees;
 mod storage;
 mod storage_types;
 mod types;
 
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+mod test_utils;
 
 use types::*;
 
+/// Step-by-step diagnostic log, compiled in only with the `trace-logs` feature
+/// and debug assertions, so a release build with the feature enabled (e.g.
+/// `--all-features`) still builds and carries no step logs. Use the
+/// `release-with-logs` profile to keep them in an optimized wasm.
+/// Error-path logs use `log!` directly so they are always emitted
+macro_rules! trace {
+    ($env:expr, $($arg:tt)*) => {
+        #[cfg(all(feature = "trace-logs", debug_assertions))]
+        log!($env, $($arg)*);
+    };
+}
+
+/// Default fixed-point scale for prices, matching Stellar's 7-decimal asset precision
+pub const DEFAULT_PRICE_SCALE: i128 = 10_000_000;
+
+/// Version of the contract's public interface, reported by get_config and
+/// bumped whenever entrypoints or their types change incompatibly
+/// Version 2 added the `approvers` argument of set_matching_engine,
+/// pause_asset, unpause_asset and set_fee_recipient; version 3 made
+/// settle_trade, settle_p2p and settle_trade_priced return a SettlementOutcome;
+/// version 4 made get_admin return an Option; version 5 removed the
+/// EitherParty auth mode; version 6 added the buyer and seller to
+/// preview_fees; version 7 removed get_trade_history_by_pair
+pub const INTERFACE_VERSION: u32 = 7;
+
+/// Largest gap, in bps of the notional, between an engine-supplied execution
+/// price and the amounts it settles
+pub const EXECUTION_TOLERANCE_BPS: u32 = 1;
+
+/// Longest span of daily buckets get_fees_in_range,
+/// get_settlements_in_range and get_volume_range will read
+pub const MAX_RANGE_DAYS: u64 = 366;
+
+/// Most records get_settlements_in_range returns per page
+pub const MAX_RANGE_RESULTS: u32 = 100;
+
+/// Most summaries get_recent_settlements returns
+pub const MAX_RECENT_SETTLEMENTS: u32 = 100;
+
+/// Most trade IDs get_settlements looks up in one call
+pub const MAX_SETTLEMENT_LOOKUP: u32 = 100;
+
+/// Most journal entries get_activity returns per page
+pub const MAX_ACTIVITY_RESULTS: u32 = 100;
+
+/// Widest [from_seq, to_seq) window get_settlement_range will walk
+pub const MAX_SEQUENCE_SPAN: u64 = 1_000;
+
+/// Longest window, in hourly buckets, get_vwap will read
+pub const MAX_VWAP_LOOKBACK_HOURS: u32 = 168;
+
 #[contract]
 pub struct SettlementContract;
 
+/// How an instruction reached the shared settlement path, which decides who
+/// must authorize it
+#[derive(Clone, Copy, PartialEq)]
+enum Submission {
+    Engine,     // settle_trade / settle_trade_priced: the matching engine
+    Batch,      // settle_trades: the engine, authorized once for the batch
+    Queued,     // process_queue: the engine, when it enqueued the instruction
+    PeerToPeer, // settle_p2p: both counterparties
+    Preview,    // preview_settle: nobody, as nothing is written
+}
+
+/// A validated settlement, worked out by plan_settlement: its price, fees
+/// and the balance changes staged (uncommitted) in `balances`
+struct SettlementPlan<'a> {
+    price: i128,
+    quantity: i128,
+    mode: SettlementMode,
+    fee_mode: FeeMode,
+    trade_fees: fees::TradeFees,
+    rebate: i128,
+    required_quote: i128,                     // Quote taken from the buyer, fee included
+    required_base: i128,                      // Base taken from the seller, fee included
+    seller_quote: i128,                       // Quote owed to the seller, net of a quote-only fee
+    fee_payer: &'a Address,
+    token_fee: Option<(&'a Address, i128)>,   // Fee charged in a separate fee asset
+    protocol_rebates: (i128, i128),           // (base, quote) paid from the protocol fee balance
+    allowances: (Option<i128>, Option<i128>, Option<i128>), // Engine allowances (buyer, seller, fee payer)
+    maker_rebates: (i128, i128),              // (buyer, seller) share of the maker rebate
+    buyer_rebate_asset: &'a Address,
+    balances: storage::BalanceBatch,
+}
+
 /// Helper function to validate that amount is positive
 /// Following pattern from Soroban token example
 fn check_positive_amount(amount: i128) {
@@ -22,6 +108,233 @@ fn check_positive_amount(amount: i128) {
     }
 }
 
+/// Helper function to derive the execution price (quote per base, scaled by the price scale)
+fn execution_price(env: &Env, base_amount: i128, quote_amount: i128) -> i128 {
+    quote_amount
+        .checked_mul(storage::get_price_scale(env))
+        .expect("Price overflow")
+        / base_amount
+}
+
+/// Canonical hash of a settlement instruction: SHA-256 over its XDR
+/// encoding. This is the digest counterparties sign, so off-chain signers
+/// must serialize the instruction to exactly the same bytes
+pub fn instruction_hash(env: &Env, instruction: &SettlementInstruction) -> BytesN<32> {
+    env.crypto().sha256(&instruction.clone().to_xdr(env)).to_bytes()
+}
+
+/// Helper function to check an engine-supplied execution price and quantity
+/// The quantity must lie within the base amount, and its notional at the
+/// price must match the quote paid for it to within
+/// EXECUTION_TOLERANCE_BPS (at least 1 unit, for rounding)
+fn is_consistent_execution(env: &Env, instruction: &SettlementInstruction, price: i128, quantity: i128) -> bool {
+    if price <= 0 || quantity <= 0 || quantity > instruction.base_amount {
+        return false;
+    }
+    let notional = price.checked_mul(quantity).expect("Price overflow") / storage::get_price_scale(env);
+    let expected = instruction
+        .quote_amount
+        .checked_mul(quantity)
+        .expect("Price overflow")
+        / instruction.base_amount;
+    let tolerance = fees::fee_for(expected, EXECUTION_TOLERANCE_BPS).max(1);
+    (notional - expected).abs() <= tolerance
+}
+
+/// Helper function to transfer tokens out of the vault without trapping
+/// Returns false if the token contract rejects the transfer
+fn try_transfer_out(env: &Env, token: &Address, to: &Address, amount: i128) -> bool {
+    use soroban_sdk::token::TokenClient;
+    let token_client = TokenClient::new(env, token);
+    matches!(
+        token_client.try_transfer(&env.current_contract_address(), to, &amount),
+        Ok(Ok(()))
+    )
+}
+
+/// Helper function to transfer tokens out of the vault, trapping on failure
+fn transfer_out(env: &Env, token: &Address, to: &Address, amount: i128) {
+    use soroban_sdk::token::TokenClient;
+    let token_client = TokenClient::new(env, token);
+    token_client.transfer(&env.current_contract_address(), to, &amount);
+}
+
+/// Helper function to resolve a fee claim against what has accrued
+/// An amount of 0 claims everything; more than accrued is rejected
+fn claim_amount(amount: i128, accrued: i128) -> i128 {
+    let amount = match amount {
+        0 => accrued,
+        _ => amount,
+    };
+    check_positive_amount(amount);
+    if amount > accrued {
+        panic!("Claim exceeds accrued fees: {} > {}", amount, accrued);
+    }
+    amount
+}
+
+/// Helper function to pay a fee claim from the protocol fee balance to the
+/// fee recipient, returning the amount paid
+fn pay_out_fees(env: &Env, asset: &Address, amount: i128, recipient: &Address) -> i128 {
+    let amount = claim_amount(amount, storage::get_fee_balance(env, asset));
+    storage::debit_fee(env, asset, amount);
+    storage::add_fees_claimed(env, asset, amount);
+    transfer_out(env, asset, recipient, amount);
+    events::emit_fees_claimed_event(env, asset, recipient, amount);
+    amount
+}
+
+/// Helper function to validate that a token is one of the supported assets
+fn check_supported_asset(env: &Env, token: &Address) {
+    if !storage::get_supported_assets(env).contains(token) {
+        panic!("Unsupported asset");
+    }
+}
+
+/// Helper function to reject a deposit that would take the user's vault
+/// balance above its cap: the user's own cap if set, else the asset's
+fn check_deposit_cap(env: &Env, user: &Address, token: &Address, amount: i128) {
+    let cap = storage::get_user_deposit_cap(env, user, token).or_else(|| storage::get_deposit_cap(env, token));
+    if let Some(cap) = cap {
+        if storage::get_balance(env, user, token) + amount > cap {
+            panic!("Deposit cap exceeded");
+        }
+    }
+}
+
+/// Helper function to pull `amount` of a token from the user into the vault,
+/// returning how much actually arrived. Fee-on-transfer and rebasing tokens
+/// can deliver less than requested, so the contract's own token balance is
+/// measured around the transfer and only the difference is credited
+fn receive_tokens(env: &Env, user: &Address, token: &Address, amount: i128) -> i128 {
+    use soroban_sdk::token::TokenClient;
+    let token_client = TokenClient::new(env, token);
+    let vault = env.current_contract_address();
+    let before = token_client.balance(&vault);
+    token_client.transfer(user, &vault, &amount);
+    let received = token_client.balance(&vault) - before;
+    if received <= 0 {
+        panic!("No tokens received");
+    }
+    received
+}
+
+/// Helper function to turn an inclusive timestamp range into the inclusive
+/// range of daily buckets it covers, bounded to MAX_RANGE_DAYS
+fn day_range(from_ts: u64, to_ts: u64) -> (u64, u64) {
+    if from_ts > to_ts {
+        panic!("Invalid range: {} > {}", from_ts, to_ts);
+    }
+    let from_day = from_ts / storage_types::FEE_DAY_SECONDS;
+    let to_day = to_ts / storage_types::FEE_DAY_SECONDS;
+    if to_day - from_day >= MAX_RANGE_DAYS {
+        panic!("Range exceeds {} days", MAX_RANGE_DAYS);
+    }
+    (from_day, to_day)
+}
+
+/// Helper function to require the matching engine's authorization
+fn require_matching_engine(env: &Env) {
+    match storage::get_matching_engine(env) {
+        Some(matching_engine) => matching_engine.require_auth(),
+        None => panic!("Matching engine not set"),
+    }
+}
+
+/// Helper function to require the matching engine's authorization for a
+/// batch or queue submission. These carry no counterparty signatures, so
+/// they are refused while AuthMode::BothParties is set
+fn require_engine_submission(env: &Env) {
+    if storage::get_auth_mode(env) == AuthMode::BothParties {
+        panic!("Auth mode requires both counterparties");
+    }
+    require_matching_engine(env);
+}
+
+/// Helper function to require the authorization settle_trade needs under
+/// the configured AuthMode
+fn require_settlement_auth(env: &Env, instruction: &SettlementInstruction) {
+    let require_both = || {
+        instruction.buy_user.require_auth();
+        instruction.sell_user.require_auth();
+    };
+    match storage::get_auth_mode(env) {
+        AuthMode::EngineOnly => require_matching_engine(env),
+        AuthMode::BothParties => require_both(),
+        AuthMode::EngineOrBoth => match storage::get_matching_engine(env) {
+            Some(matching_engine) => matching_engine.require_auth(),
+            None => require_both(),
+        },
+    }
+}
+
+/// Release as much of a user's locked balance as a settlement debit of
+/// `amount` used up
+fn consume_lock(env: &Env, user: &Address, asset: &Address, amount: i128) {
+    let used = storage::get_locked_balance(env, user, asset).min(amount);
+    if used > 0 {
+        let locked = storage::adjust_locked_balance(env, user, asset, -used);
+        events::emit_lock_changed_event(env, user, asset, -used, locked);
+    }
+}
+
+/// Helper function to authorize a sensitive admin operation
+/// Without admin signers the admin's auth is required. Otherwise at least
+/// the threshold of distinct registered signers, listed in `approvers`, must
+/// each authorize this invocation. Returns the addresses that authorized it
+fn require_admin_approval(env: &Env, approvers: &Option<Vec<Address>>) -> Vec<Address> {
+    let threshold = storage::get_admin_threshold(env);
+    if threshold == 0 {
+        let admin = storage::get_admin(env);
+        admin.require_auth();
+        return Vec::from_array(env, [admin]);
+    }
+
+    let signers = storage::get_admin_signers(env);
+    let approvers = match approvers {
+        Some(approvers) => approvers,
+        None => panic!("Admin approvers required"),
+    };
+    let mut approved: Vec<Address> = Vec::new(env);
+    for approver in approvers.iter() {
+        if !signers.contains(&approver) {
+            panic!("Not an admin signer");
+        }
+        if approved.contains(&approver) {
+            panic!("Duplicate approver");
+        }
+        approver.require_auth();
+        approved.push_back(approver);
+    }
+    if approved.len() < threshold {
+        panic!("Not enough admin approvals: {} of {}", approved.len(), threshold);
+    }
+    approved
+}
+
+/// Helper function to check a pair asset may be replaced by `token`
+/// Requires the admin's auth, a token not already supported and nothing
+/// owed in the asset being replaced. Returns the admin
+fn check_pair_asset_replaceable(env: &Env, previous: &Address, token: &Address) -> Address {
+    let admin = storage::get_admin(env);
+    admin.require_auth();
+    if storage::get_supported_assets(env).contains(token) {
+        panic!("Asset already supported");
+    }
+    let liability = storage::get_liability(env, previous);
+    if liability != 0 {
+        panic!("Asset has balances: {}", liability);
+    }
+    admin
+}
+
+/// Helper function to reject operations on a paused asset
+fn check_asset_not_paused(env: &Env, token: &Address) {
+    if storage::is_asset_paused(env, token) {
+        panic!("Asset paused");
+    }
+}
+
 #[contractimpl]
 impl SettlementContract {
     /// Constructor function that runs automatically during deployment
@@ -29,183 +342,2281 @@ impl SettlementContract {
     /// This is called automatically when constructor arguments are provided to
     /// `stellar contract deploy`. For example:
     /// `stellar contract deploy --wasm ... -- --admin <admin_address> --token_a <addr> --token_b <addr>`
-    pub fn __constructor(env: Env, admin: Address, token_a: Address, token_b: Address) {
+    ///
+    /// `price_scale` is the fixed-point scale for execution prices; it defaults to
+    /// 10^7 (Stellar's 7-decimal precision) and should match the pair's token decimals
+    pub fn __constructor(env: Env, admin: Address, token_a: Address, token_b: Address, price_scale: Option<i128>) {
+        // Never let a second initialization silently replace the admin or assets
+        if storage::is_initialized(&env) {
+            panic!("already initialized");
+        }
+        storage::set_initialized(&env);
+
+        let price_scale = price_scale.unwrap_or(DEFAULT_PRICE_SCALE);
+        if price_scale <= 0 {
+            panic!("Price scale must be positive: {}", price_scale);
+        }
         storage::set_admin(&env, &admin);
-        env.storage().instance().set(&storage_types::DataKey::AssetA, &token_a);
-        env.storage().instance().set(&storage_types::DataKey::AssetB, &token_b);
+        storage::set_price_scale(&env, price_scale);
+        storage::set_asset_a(&env, &token_a);
+        storage::set_asset_b(&env, &token_b);
     }
 
     /// Set the matching engine address (authorized to call settle_trade)
-    /// Only admin can call this
-    pub fn set_matching_engine(env: Env, matching_engine: Address) {
+    /// Only admin can call this; with admin signers configured, `approvers`
+    /// must name enough of them to meet the threshold
+    pub fn set_matching_engine(env: Env, matching_engine: Address, approvers: Option<Vec<Address>>) {
+        let approvers = require_admin_approval(&env, &approvers);
+        let previous = storage::get_matching_engine(&env);
+        storage::set_matching_engine(&env, &matching_engine);
+        events::emit_engine_changed_event(&env, previous, &matching_engine, approvers);
+    }
+
+    /// Require M-of-N approval from `signers` (M = `threshold`) instead of
+    /// the admin alone for sensitive operations. A threshold of 0 with no
+    /// signers hands them back to the admin. Changing the set needs the same
+    /// approval as any other sensitive operation
+    pub fn set_admin_signers(env: Env, signers: Vec<Address>, threshold: u32, approvers: Option<Vec<Address>>) {
+        let approvers = require_admin_approval(&env, &approvers);
+        if threshold == 0 && !signers.is_empty() {
+            panic!("Threshold must be positive");
+        }
+        if threshold > signers.len() {
+            panic!("Threshold exceeds signer count: {} > {}", threshold, signers.len());
+        }
+        for (i, signer) in signers.iter().enumerate() {
+            if signers.first_index_of(&signer) != Some(i as u32) {
+                panic!("Duplicate admin signer");
+            }
+        }
+        let previous = (storage::get_admin_signers(&env), storage::get_admin_threshold(&env));
+        storage::set_admin_signers(&env, &signers, threshold);
+        events::emit_admin_signers_changed_event(&env, previous, &signers, threshold, approvers);
+    }
+
+    /// Replace the contract's code with an already uploaded Wasm, keeping its
+    /// storage. Needs admin approval (the admin signers' threshold once
+    /// configured)
+    pub fn upgrade(env: Env, new_wasm_hash: BytesN<32>, approvers: Option<Vec<Address>>) {
+        let approvers = require_admin_approval(&env, &approvers);
+        env.deployer().update_current_contract_wasm(new_wasm_hash.clone());
+        events::emit_upgraded_event(&env, &new_wasm_hash, approvers);
+    }
+
+    /// Get the admin signers (empty while the admin alone decides)
+    pub fn get_admin_signers(env: Env) -> Vec<Address> {
+        storage::get_admin_signers(&env)
+    }
+
+    /// Get the number of admin signer approvals required (0 = admin alone)
+    pub fn get_admin_threshold(env: Env) -> u32 {
+        storage::get_admin_threshold(&env)
+    }
+
+    /// Move trade histories written to instance storage by earlier contract
+    /// versions into per-user persistent entries. One-shot per user; users
+    /// without legacy data are skipped. Only admin can call this
+    pub fn migrate_trade_history(env: Env, users: Vec<Address>) -> u32 {
         let admin = storage::get_admin(&env);
         admin.require_auth();
-        storage::set_matching_engine(&env, &matching_engine);
+
+        let mut migrated = 0;
+        for user in users.iter() {
+            if storage::migrate_user_trade_history(&env, &user) {
+                migrated += 1;
+            }
+        }
+        migrated
     }
 
-    /// Deposit assets into the contract vault
-    /// User must approve the contract to transfer tokens before calling this
-    pub fn deposit(env: Env, user: Address, token: Address, amount: i128) {
-        user.require_auth();
-        check_positive_amount(amount);
+    /// Seed the net flows verify_user_consistency reconciles against from
+    /// the current balances of `users`, for balances that earlier contract
+    /// versions wrote without recording them. An asset that already has
+    /// recorded flows is left alone; users with nothing to seed are skipped.
+    /// Returns the number of users seeded. Only admin can call this
+    pub fn seed_net_flows(env: Env, users: Vec<Address>) -> u32 {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
 
-        // Verify token is supported
-        let asset_a = storage::get_asset_a(&env);
-        let asset_b = storage::get_asset_b(&env);
-        if token != asset_a && token != asset_b {
-            panic!("Unsupported asset");
+        let mut seeded = 0;
+        for user in users.iter() {
+            if storage::seed_user_net_flows(&env, &user) {
+                seeded += 1;
+            }
         }
+        seeded
+    }
 
-        // Transfer tokens from user to contract
-        use soroban_sdk::token::TokenClient;
-        let token_client = TokenClient::new(&env, &token);
-        token_client.transfer(&user, &env.current_contract_address(), &amount);
+    /// List the nonzero vault balances of `users` in an asset, for seeding a
+    /// newly deployed contract version with import_balances.
+    /// Only admin can call this
+    pub fn export_balances(env: Env, users: Vec<Address>, asset: Address) -> Vec<(Address, i128)> {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
 
-        // Update user balance in vault
-        storage::add_balance(&env, &user, &token, amount);
+        let mut entries = Vec::new(&env);
+        for user in users.iter() {
+            let balance = storage::get_balance(&env, &user, &asset);
+            if balance != 0 {
+                entries.push_back((user, balance));
+            }
+        }
+        entries
+    }
 
-        events::emit_deposit_event(&env, &user, &token, amount);
+    /// Seed vault balances exported from a previous contract version. Only
+    /// runs before normal operation: until finish_import is called or the
+    /// first settlement is recorded. Each user must not already hold a
+    /// balance in the asset. The tokens backing the balances must be moved
+    /// to this contract separately. Only admin can call this
+    pub fn import_balances(env: Env, entries: Vec<(Address, i128)>, asset: Address) {
+        storage::extend_instance_ttl(&env);
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        if storage::is_import_closed(&env) {
+            panic!("Balance import closed");
+        }
+        check_supported_asset(&env, &asset);
+
+        for (user, amount) in entries.iter() {
+            check_positive_amount(amount);
+            if storage::get_balance(&env, &user, &asset) != 0 {
+                panic!("Balance already set");
+            }
+            storage::set_balance(&env, &user, &asset, amount);
+            storage::record_net_flows(&env, &user, &[(&asset, amount)]);
+            storage::record_activity(&env, &user, ActivityKind::Import, None, &[(&asset, amount)]);
+        }
     }
 
-    /// Withdraw assets from the contract vault
-    pub fn withdraw(env: Env, user: Address, token: Address, amount: i128) {
-        user.require_auth();
-        check_positive_amount(amount);
+    /// Permanently close balance imports. Only admin can call this
+    pub fn finish_import(env: Env) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        storage::close_import(&env);
+        events::emit_import_closed_event(&env, &admin);
+    }
 
-        // Check user has sufficient balance
-        let balance = storage::get_balance(&env, &user, &token);
-        if balance < amount {
-            panic!("Insufficient balance");
+    /// Register an additional supported asset, such as a dedicated fee token
+    /// It can be deposited, withdrawn and charged as an instruction's
+    /// fee_asset; trades still settle in the asset_a/asset_b pair.
+    /// Only admin can call this
+    pub fn add_supported_asset(env: Env, asset: Address) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        if storage::get_supported_assets(&env).contains(&asset) {
+            panic!("Asset already supported");
         }
+        let mut extra_assets = storage::get_extra_assets(&env);
+        extra_assets.push_back(asset.clone());
+        storage::set_extra_assets(&env, &extra_assets);
+        events::emit_asset_added_event(&env, &asset, &admin);
+    }
 
-        // Update user balance in vault
-        storage::subtract_balance(&env, &user, &token, amount);
+    /// Replace asset_a, e.g. after deploying with a wrong token address
+    /// Only allowed while the vault owes nothing in the current asset (see
+    /// health), so no funds are orphaned. Only admin can call this
+    pub fn set_asset_a(env: Env, token: Address) {
+        let previous = storage::get_asset_a(&env);
+        let admin = check_pair_asset_replaceable(&env, &previous, &token);
+        storage::set_asset_a(&env, &token);
+        events::emit_asset_changed_event(&env, 0, &previous, &token, &admin);
+    }
 
-        // Transfer tokens from contract to user
-        use soroban_sdk::token::TokenClient;
-        let token_client = TokenClient::new(&env, &token);
-        token_client.transfer(&env.current_contract_address(), &user, &amount);
+    /// Replace asset_b, under the same rules as set_asset_a
+    pub fn set_asset_b(env: Env, token: Address) {
+        let previous = storage::get_asset_b(&env);
+        let admin = check_pair_asset_replaceable(&env, &previous, &token);
+        storage::set_asset_b(&env, &token);
+        events::emit_asset_changed_event(&env, 1, &previous, &token, &admin);
+    }
 
-        events::emit_withdraw_event(&env, &user, &token, amount);
+    /// Get every supported asset: asset_a, asset_b, then any registered since
+    pub fn get_supported_assets(env: Env) -> Vec<Address> {
+        storage::get_supported_assets(&env)
     }
 
-    /// Get user balance for a specific asset
-    pub fn get_balance(env: Env, user: Address, token: Address) -> i128 {
-        storage::get_balance(&env, &user, &token)
+    /// Pause deposits and settlements touching an asset
+    /// Withdrawals of the asset remain available. Only admin (or the admin
+    /// signers, see set_matching_engine) can call this
+    pub fn pause_asset(env: Env, token: Address, approvers: Option<Vec<Address>>) {
+        let approvers = require_admin_approval(&env, &approvers);
+        let previous = storage::is_asset_paused(&env, &token);
+        storage::set_asset_paused(&env, &token, true);
+        events::emit_paused_changed_event(&env, &token, previous, true, approvers);
     }
 
-    /// Get supported Asset A
-    pub fn get_asset_a(env: Env) -> Address {
-        storage::get_asset_a(&env)
+    /// Resume deposits and settlements touching an asset
+    /// Only admin (or the admin signers) can call this
+    pub fn unpause_asset(env: Env, token: Address, approvers: Option<Vec<Address>>) {
+        let approvers = require_admin_approval(&env, &approvers);
+        let previous = storage::is_asset_paused(&env, &token);
+        storage::set_asset_paused(&env, &token, false);
+        events::emit_paused_changed_event(&env, &token, previous, false, approvers);
     }
 
-    /// Get supported Asset B
-    pub fn get_asset_b(env: Env) -> Address {
-        storage::get_asset_b(&env)
+    /// Check whether an asset is paused
+    pub fn is_asset_paused(env: Env, token: Address) -> bool {
+        storage::is_asset_paused(&env, &token)
     }
 
-    /// Settle a trade
-    /// Can be called by matching engine (authorized) or users
-    pub fn settle_trade(env: Env, instruction: SettlementInstruction) -> SettlementResult {
-        log!(&env, "settle_trade: Starting settlement");
+    /// Choose whether settlements credit vault balances or transfer
+    /// proceeds directly to the counterparties. Only admin can call this
+    pub fn set_settlement_mode(env: Env, mode: SettlementMode) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::get_settlement_mode(&env);
+        storage::set_settlement_mode(&env, mode);
+        events::emit_settlement_mode_changed_event(&env, previous, mode, &admin);
+    }
 
-        // Verify assets match supported assets
-        let asset_a = storage::get_asset_a(&env);
-        let asset_b = storage::get_asset_b(&env);
-        let base = &instruction.base_asset;
-        let quote = &instruction.quote_asset;
+    /// Get the current settlement mode
+    pub fn get_settlement_mode(env: Env) -> SettlementMode {
+        storage::get_settlement_mode(&env)
+    }
 
-        log!(&env, "settle_trade: Checking asset support");
-        if (base != &asset_a && base != &asset_b) || (quote != &asset_a && quote != &asset_b) {
-             log!(&env, "settle_trade: ERROR - Unsupported asset in trade");
-             return SettlementResult::InvalidMatchingProof;
+    /// Choose who must authorize settle_trade and settle_trade_priced:
+    /// `EngineOnly` (the matching engine, the default), `BothParties` (buyer
+    /// and seller), or `EngineOrBoth` (the engine, with both counterparties
+    /// as the fallback while no engine is set). There is no taker-only mode:
+    /// a taker's signature says nothing about the counterparty whose funds
+    /// also move, so it would let one user settle against another.
+    /// settle_p2p always needs both counterparties. Batches and the queue
+    /// need the engine and are refused under `BothParties`, since they carry
+    /// no counterparty signatures. Only admin can call this
+    pub fn set_auth_mode(env: Env, mode: AuthMode) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::get_auth_mode(&env);
+        storage::set_auth_mode(&env, mode);
+        events::emit_auth_mode_changed_event(&env, previous, mode, &admin);
+    }
+
+    /// Get the current settlement authorization mode
+    pub fn get_auth_mode(env: Env) -> AuthMode {
+        storage::get_auth_mode(&env)
+    }
+
+    /// Choose whether fees are charged per leg or entirely in the quote asset
+    /// In quote-only mode the seller's fee is deducted from the quote it
+    /// receives and instructions must not carry a base fee
+    /// Only the fee manager (or admin, if none is set) can call this
+    pub fn set_fee_mode(env: Env, mode: FeeMode) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        storage::set_fee_mode(&env, mode);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
+
+    /// Get the current fee mode
+    pub fn get_fee_mode(env: Env) -> FeeMode {
+        storage::get_fee_mode(&env)
+    }
+
+    /// Choose how fees computed from on-chain rates round a fractional
+    /// remainder: down (the default), up, or to the nearest unit with halves
+    /// up. Instruction-supplied fees and the fee splits are unaffected
+    /// Only the fee manager (or admin, if none is set) can call this
+    pub fn set_rounding_mode(env: Env, mode: RoundingMode) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        storage::set_rounding_mode(&env, mode);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
+
+    /// Get the current fee rounding mode
+    pub fn get_rounding_mode(env: Env) -> RoundingMode {
+        storage::get_rounding_mode(&env)
+    }
+
+    /// Set the address allowed to change fee parameters
+    /// Only admin can call this
+    pub fn set_fee_manager(env: Env, fee_manager: Address) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::get_fee_manager(&env);
+        storage::set_fee_manager(&env, &fee_manager);
+        events::emit_role_changed_event(&env, "fee_manager", previous, &fee_manager, Vec::from_array(&env, [admin]));
+    }
+
+    /// Set the address that receives claimed protocol fees
+    /// Only admin (or the admin signers) can call this
+    pub fn set_fee_recipient(env: Env, fee_recipient: Address, approvers: Option<Vec<Address>>) {
+        let approvers = require_admin_approval(&env, &approvers);
+        let previous = storage::get_fee_recipient(&env);
+        storage::set_fee_recipient(&env, &fee_recipient);
+        events::emit_role_changed_event(&env, "fee_recipient", previous, &fee_recipient, approvers);
+    }
+
+    /// Get the fee recipient (the admin until one is set)
+    pub fn get_fee_recipient(env: Env) -> Address {
+        storage::get_fee_recipient(&env).unwrap_or_else(|| storage::get_admin(&env))
+    }
+
+    /// Pay accrued protocol fees in an asset out to the fee recipient
+    /// `amount` of 0 claims the whole fee balance. Returns the amount paid.
+    /// Only the fee recipient (or admin if none is set) can call this
+    pub fn claim_fees(env: Env, asset: Address, amount: i128) -> i128 {
+        storage::extend_instance_ttl(&env);
+        let recipient = Self::get_fee_recipient(env.clone());
+        recipient.require_auth();
+        pay_out_fees(&env, &asset, amount, &recipient)
+    }
+
+    /// Pay accrued protocol fees out to the fee recipient on the admin's
+    /// authority, exactly like claim_fees. The fees still go to the fee
+    /// recipient. Only admin can call this
+    pub fn admin_claim_fees(env: Env, asset: Address, amount: i128) -> i128 {
+        storage::extend_instance_ttl(&env);
+        storage::get_admin(&env).require_auth();
+        let recipient = Self::get_fee_recipient(env.clone());
+        pay_out_fees(&env, &asset, amount, &recipient)
+    }
+
+    /// Split each settlement fee: `insurance_bps` of it to the insurance
+    /// fund and `referrer_bps` to the paying user's referrer (if registered), with
+    /// the remainder to the protocol fee balance. Shares are in basis points
+    /// of the fee, round down, and may sum to at most 10000.
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_fee_split(env: Env, insurance_bps: u32, referrer_bps: u32) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        let split = FeeSplit { insurance_bps, referrer_bps };
+        if !fees::fee_shares_fit(&split, storage::get_referral_bps(&env)) {
+            panic!("Fee split exceeds 100%: {} + {}", insurance_bps, referrer_bps);
         }
+        storage::set_fee_split(&env, &split);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
 
-        log!(&env, "settle_trade: Verifying matching engine authorization");
-        match storage::get_matching_engine(&env) {
-            Some(matching_engine) => matching_engine.require_auth(),
-            None => panic!("Matching engine not set"),
+    /// Get the current fee split (all to the protocol until set)
+    pub fn get_fee_split(env: Env) -> FeeSplit {
+        storage::get_fee_split(&env)
+    }
+
+    /// Rebate `referral_bps` of each quote fee a trade pays to the referrer
+    /// its instruction names, credited to the referrer's vault balance out of
+    /// the protocol's share. Together with the fee split it may not exceed
+    /// 10000. Only the fee manager (or admin if none is set) can call this
+    pub fn set_referral_bps(env: Env, referral_bps: u32) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        if !fees::fee_shares_fit(&storage::get_fee_split(&env), referral_bps) {
+            panic!("Referral rebate exceeds the protocol share: {}", referral_bps);
         }
+        storage::set_referral_bps(&env, referral_bps);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
 
-        // Skip signature and proof verification for now
-        log!(&env, "settle_trade: Skipping verification (simplified flow)");
-        // 4. Check vault balances
-        log!(&env, "settle_trade: Step 5 - Checking vault balances");
-        let buy_balance = storage::get_balance(&env, &instruction.buy_user, &instruction.quote_asset);
-        let sell_balance = storage::get_balance(&env, &instruction.sell_user, &instruction.base_asset);
-        
-        let required_quote = instruction.quote_amount + instruction.fee_quote;
-        let required_base = instruction.base_amount + instruction.fee_base;
+    /// Get the referral rebate rate in bps (0 until set)
+    pub fn get_referral_bps(env: Env) -> u32 {
+        storage::get_referral_bps(&env)
+    }
+
+    /// Set the address that manages the insurance fund
+    /// Only admin can call this
+    pub fn set_insurance_admin(env: Env, insurance_admin: Address) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::get_insurance_admin(&env);
+        storage::set_insurance_admin(&env, &insurance_admin);
+        events::emit_role_changed_event(&env, "insurance_admin", previous, &insurance_admin, Vec::from_array(&env, [admin]));
+    }
 
-        log!(&env, "settle_trade: Checking buyer quote balance and seller base balance");
+    /// Get the insurance fund admin (the admin until one is set)
+    pub fn get_insurance_admin(env: Env) -> Address {
+        storage::get_insurance_admin(&env).unwrap_or_else(|| storage::get_admin(&env))
+    }
 
-        if buy_balance < required_quote {
-            log!(&env, "settle_trade: ERROR - Buyer has insufficient quote balance");
-            log!(&env, "settle_trade: Buyer balance less than required quote amount, returning InsufficientBalance");
-            return SettlementResult::InsufficientBalance;
+    /// Insurance fund share of fees accrued in an asset and not yet paid out
+    pub fn get_insurance_balance(env: Env, asset: Address) -> i128 {
+        storage::get_insurance_balance(&env, &asset)
+    }
+
+    /// Pay the insurance fund balance in an asset out to the insurance admin
+    /// `amount` of 0 claims everything. Returns the amount paid.
+    /// Only the insurance admin (or admin if none is set) can call this
+    pub fn claim_insurance(env: Env, asset: Address, amount: i128) -> i128 {
+        storage::extend_instance_ttl(&env);
+        let insurance_admin = Self::get_insurance_admin(env.clone());
+        insurance_admin.require_auth();
+
+        let amount = claim_amount(amount, storage::get_insurance_balance(&env, &asset));
+        storage::adjust_insurance_balance(&env, &asset, -amount);
+        storage::add_fees_claimed(&env, &asset, amount);
+        transfer_out(&env, &asset, &insurance_admin, amount);
+
+        events::emit_insurance_claimed_event(&env, &asset, &insurance_admin, amount);
+        amount
+    }
+
+    /// Compensate `recipient` out of the insurance fund, e.g. after an
+    /// incident, tagged with an off-chain `reason_code`. Only the insurance
+    /// balance is drawn on, never user or protocol fee balances.
+    /// Needs admin approval (the admin signers' threshold once configured)
+    pub fn pay_from_insurance(
+        env: Env,
+        asset: Address,
+        recipient: Address,
+        amount: i128,
+        reason_code: u32,
+        approvers: Option<Vec<Address>>,
+    ) {
+        storage::extend_instance_ttl(&env);
+        require_admin_approval(&env, &approvers);
+        check_positive_amount(amount);
+        if amount > storage::get_insurance_balance(&env, &asset) {
+            panic!("Payout exceeds insurance balance");
         }
 
-        if sell_balance < required_base {
-            log!(&env, "settle_trade: ERROR - Seller has insufficient base balance");
-            log!(&env, "settle_trade: Seller balance less than required base amount, returning InsufficientBalance");
-            return SettlementResult::InsufficientBalance;
+        storage::adjust_insurance_balance(&env, &asset, -amount);
+        storage::add_fees_claimed(&env, &asset, amount);
+        transfer_out(&env, &asset, &recipient, amount);
+
+        events::emit_insurance_payout_event(&env, &asset, &recipient, amount, reason_code);
+    }
+
+    /// Register the address that referred a user. It earns the referrer
+    /// share of every fee the user pays, as maker or taker. Can be set only
+    /// once and never changes afterwards
+    pub fn register_referrer(env: Env, user: Address, referrer: Address) {
+        user.require_auth();
+        if user == referrer {
+            panic!("Cannot refer yourself");
         }
+        if storage::get_referrer(&env, &user).is_some() {
+            panic!("Referrer already set");
+        }
+        storage::set_referrer(&env, &user, &referrer);
+    }
 
-        log!(&env, "settle_trade: All balance checks passed");
+    /// Get the address that referred a user, if any
+    pub fn get_referrer(env: Env, user: Address) -> Option<Address> {
+        storage::get_referrer(&env, &user)
+    }
 
-        // 5. Execute asset transfers from vault
-        log!(&env, "settle_trade: Step 5 - Executing asset transfers");
-        // Buyer pays quote asset, receives base asset
-        log!(&env, "settle_trade: Transferring quote from buyer");
-        storage::subtract_balance(&env, &instruction.buy_user, &instruction.quote_asset, required_quote);
-        log!(&env, "settle_trade: Transferring base to buyer");
-        storage::add_balance(&env, &instruction.buy_user, &instruction.base_asset, instruction.base_amount);
+    /// Referral fees owed to a referrer in an asset and not yet paid out
+    pub fn get_referral_balance(env: Env, referrer: Address, asset: Address) -> i128 {
+        storage::get_referral_balance(&env, &referrer, &asset)
+    }
 
-        // Seller pays base asset, receives quote asset
-        log!(&env, "settle_trade: Transferring base from seller");
-        storage::subtract_balance(&env, &instruction.sell_user, &instruction.base_asset, required_base);
-        log!(&env, "settle_trade: Transferring quote to seller");
-        storage::add_balance(&env, &instruction.sell_user, &instruction.quote_asset, instruction.quote_amount);
-        log!(&env, "settle_trade: Asset transfers completed");
-
-        // 6. Collect fees (transfer to admin or fee recipient)
-        log!(&env, "settle_trade: Step 6 - Collecting fees");
-        if instruction.fee_base > 0 || instruction.fee_quote > 0 {
-            let admin = storage::get_admin(&env);
-            if instruction.fee_base > 0 {
-                log!(&env, "settle_trade: Collecting base fee");
-                storage::add_balance(&env, &admin, &instruction.base_asset, instruction.fee_base);
-            }
-            if instruction.fee_quote > 0 {
-                log!(&env, "settle_trade: Collecting quote fee");
-                storage::add_balance(&env, &admin, &instruction.quote_asset, instruction.fee_quote);
-            }
-            log!(&env, "settle_trade: Fees collected");
-        } else {
-            log!(&env, "settle_trade: No fees to collect");
+    /// Pay a referrer's accrued referral fees in an asset out to them
+    /// `amount` of 0 claims everything. Returns the amount paid
+    pub fn claim_referral_fees(env: Env, referrer: Address, asset: Address, amount: i128) -> i128 {
+        storage::extend_instance_ttl(&env);
+        referrer.require_auth();
+
+        let amount = claim_amount(amount, storage::get_referral_balance(&env, &referrer, &asset));
+        storage::adjust_referral_balance(&env, &referrer, &asset, -amount);
+        storage::add_fees_claimed(&env, &asset, amount);
+        transfer_out(&env, &asset, &referrer, amount);
+
+        events::emit_referral_claimed_event(&env, &asset, &referrer, amount);
+        amount
+    }
+
+    /// Set a single on-chain fee rate for both makers and takers
+    /// Once set, settle_trade computes fees itself and ignores instruction fees.
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_fee_bps(env: Env, bps: u32) {
+        Self::set_fee_rates(env, bps, bps);
+    }
+
+    /// Set distinct maker and taker fee rates in basis points (each at most
+    /// 1000 = 10%; the maker rate may be zero)
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_fee_rates(env: Env, maker_bps: u32, taker_bps: u32) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        if maker_bps > fees::MAX_FEE_BPS || taker_bps > fees::MAX_FEE_BPS {
+            panic!("Fee bps exceeds maximum");
         }
+        storage::set_fee_rates(&env, &FeeRates { maker_bps, taker_bps });
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
 
-        // 7. Record settlement
-        log!(&env, "settle_trade: Step 7 - Recording settlement");
-        storage::record_settlement(&env, &instruction);
-        log!(&env, "settle_trade: Settlement recorded");
+    /// Get the on-chain fee rate set with set_fee_bps; with distinct maker
+    /// and taker rates this is the taker rate (`None` while fees are
+    /// instruction-supplied)
+    pub fn get_fee_bps(env: Env) -> Option<u32> {
+        storage::get_fee_rates(&env).map(|rates| rates.taker_bps)
+    }
 
-        // 8. Emit events
-        log!(&env, "settle_trade: Step 8 - Emitting events");
-        events::emit_settlement_event(&env, &instruction);
-        log!(&env, "settle_trade: Events emitted");
+    /// Get the on-chain fee rates (`None` while fees are instruction-supplied)
+    pub fn get_fee_rates(env: Env) -> Option<FeeRates> {
+        storage::get_fee_rates(&env)
+    }
 
-        log!(&env, "settle_trade: Settlement completed successfully");
-        SettlementResult::Success
+    /// Override the fee rates for one trading pair; either ordering of the
+    /// pair's assets names the same override. Same bounds as set_fee_rates.
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_pair_fee_bps(env: Env, base: Address, quote: Address, maker_bps: u32, taker_bps: u32) {
+        let actor = fees::require_fee_authority(&env);
+        check_supported_asset(&env, &base);
+        check_supported_asset(&env, &quote);
+        if base == quote {
+            panic!("Pair assets must differ");
+        }
+        if maker_bps > fees::MAX_FEE_BPS || taker_bps > fees::MAX_FEE_BPS {
+            panic!("Fee bps exceeds maximum");
+        }
+        let pair = storage::pair_key(&env, &base, &quote);
+        let previous = storage::get_pair_fee_rates(&env, &pair);
+        let rates = FeeRates { maker_bps, taker_bps };
+        storage::set_pair_fee_rates(&env, &pair, &rates);
+        events::emit_pair_fee_changed_event(&env, &pair, previous, Some(rates), &actor);
     }
 
-    /// Query trade history for a user
-    pub fn get_trade_history(env: Env, user: Address, limit: u32) -> Vec<SettlementRecord> {
-        storage::get_trade_history(&env, &user, limit)
+    /// Remove a pair's fee override so it reverts to the global rates
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn remove_pair_fee_bps(env: Env, base: Address, quote: Address) {
+        let actor = fees::require_fee_authority(&env);
+        let pair = storage::pair_key(&env, &base, &quote);
+        let previous = storage::get_pair_fee_rates(&env, &pair);
+        storage::remove_pair_fee_rates(&env, &pair);
+        events::emit_pair_fee_changed_event(&env, &pair, previous, None, &actor);
     }
 
-    /// Get a settlement record by trade ID
-    pub fn get_settlement(env: Env, trade_id: BytesN<32>) -> Option<SettlementRecord> {
-        storage::get_settlement(&env, &trade_id)
+    /// Fee rates settle_trade applies to a pair: its override, else the
+    /// global rates (`None` while fees are instruction-supplied)
+    pub fn get_effective_fee(env: Env, base: Address, quote: Address) -> Option<FeeRates> {
+        fees::effective_rates(&env, &base, &quote)
+    }
+
+    /// Set volume fee tiers as (minimum volume, bps) entries, ascending by
+    /// volume. A side's on-chain rate is lowered to the bps of the highest
+    /// tier its user's settled quote volume in the current 30-day epoch
+    /// reaches. An empty table removes the tiers.
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_fee_tiers(env: Env, tiers: Vec<(i128, u32)>) {
+        let actor = fees::require_fee_authority(&env);
+        if tiers.len() > fees::MAX_FEE_TIERS {
+            panic!("Too many fee tiers: {}", tiers.len());
+        }
+        let mut previous: Option<i128> = None;
+        for (min_volume, bps) in tiers.iter() {
+            if min_volume < 0 || previous.is_some_and(|previous| min_volume <= previous) {
+                panic!("Fee tier volumes must be ascending and non-negative");
+            }
+            if bps > fees::MAX_FEE_BPS {
+                panic!("Fee bps exceeds maximum");
+            }
+            previous = Some(min_volume);
+        }
+        let previous = storage::get_fee_tiers(&env);
+        storage::set_fee_tiers(&env, &tiers);
+        events::emit_fee_tiers_changed_event(&env, previous, &tiers, &actor);
+    }
+
+    /// Get the volume fee tiers (empty while none are set)
+    pub fn get_fee_tiers(env: Env) -> Vec<(i128, u32)> {
+        storage::get_fee_tiers(&env)
+    }
+
+    /// Settled quote volume a user has built up in the current epoch
+    pub fn get_user_volume(env: Env, user: Address) -> i128 {
+        storage::get_user_volume(&env, &user)
+    }
+
+    /// A user's settled quote volume over their lifetime and in the current
+    /// epoch, for external rewards programs. Each trade counts its quote
+    /// notional once for each side; unlike get_user_volume it is tracked
+    /// whether or not fee tiers are configured
+    pub fn get_user_volume_stats(env: Env, user: Address) -> UserVolumeStats {
+        storage::get_user_volume_stats(&env, &user)
+    }
+
+    /// Set the length in seconds of get_user_volume_stats epochs (30 days
+    /// until set). Only admin can call this
+    pub fn set_volume_epoch_length(env: Env, seconds: u64) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        if seconds == 0 {
+            panic!("Epoch length must be positive");
+        }
+        let previous = storage::get_volume_epoch_length(&env);
+        storage::set_volume_epoch_length(&env, seconds);
+        events::emit_setting_changed_event(&env, "volume_epoch", previous.into(), seconds.into(), &admin);
+    }
+
+    /// Get the length in seconds of get_user_volume_stats epochs
+    pub fn get_volume_epoch_length(env: Env) -> u64 {
+        storage::get_volume_epoch_length(&env)
+    }
+
+    /// The (minimum volume, bps) tier a user currently qualifies for, if any
+    pub fn get_user_tier(env: Env, user: Address) -> Option<(i128, u32)> {
+        fees::user_tier(&env, &storage::get_fee_tiers(&env), &user)
+    }
+
+    /// Pay makers a rebate of `bps` of their leg in the asset of the taker's
+    /// fee, credited to their vault balance and funded from that fee. The
+    /// rebate never exceeds the taker fee charged on the trade. 0 disables it.
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_maker_rebate_bps(env: Env, bps: u32) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        if bps > fees::MAX_FEE_BPS {
+            panic!("Fee bps exceeds maximum");
+        }
+        storage::set_maker_rebate_bps(&env, bps);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
+
+    /// Reward keepers `amount` of an asset per trade they settle through
+    /// settle_trades, paid from the protocol fee balance. 0 disables it.
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_keeper_reward(env: Env, asset: Address, amount: i128) {
+        let actor = fees::require_fee_authority(&env);
+        check_supported_asset(&env, &asset);
+        if amount < 0 {
+            panic!("Keeper reward must not be negative: {}", amount);
+        }
+        let previous = storage::get_keeper_reward(&env, &asset);
+        storage::set_keeper_reward(&env, &asset, amount);
+        events::emit_keeper_reward_changed_event(&env, &asset, previous, amount, &actor);
+    }
+
+    /// Get the keeper reward per settled trade in an asset (0 = none)
+    pub fn get_keeper_reward(env: Env, asset: Address) -> i128 {
+        storage::get_keeper_reward(&env, &asset)
+    }
+
+    /// Get the maker rebate rate (0 = no rebates)
+    pub fn get_maker_rebate_bps(env: Env) -> u32 {
+        storage::get_maker_rebate_bps(&env)
+    }
+
+    /// Cap instruction-supplied fees at `bps` of each leg's amount
+    /// Only the fee manager (or admin if none is set) can call this
+    pub fn set_max_fee_bps(env: Env, bps: u32) {
+        let actor = fees::require_fee_authority(&env);
+        let previous = fees::fee_params(&env);
+        if bps as i128 > fees::BPS_DENOMINATOR {
+            panic!("Fee cap exceeds 100%: {}", bps);
+        }
+        storage::set_max_fee_bps(&env, bps);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &actor);
+    }
+
+    /// Get the cap on instruction-supplied fees (`None` means unlimited)
+    pub fn get_max_fee_bps(env: Env) -> Option<u32> {
+        storage::get_max_fee_bps(&env)
+    }
+
+    /// Cap a settlement's total fee at `bps` of its notional, whatever rates
+    /// or instruction fees produced it, as a last line of defense against
+    /// fat-finger fees; `None` removes the cap. Only admin can call this
+    pub fn set_max_fee_ratio_bps(env: Env, bps: Option<u32>) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        if bps.is_some_and(|bps| bps as i128 > fees::BPS_DENOMINATOR) {
+            panic!("Fee ratio cap exceeds 100%");
+        }
+        let previous = fees::fee_params(&env);
+        storage::set_max_fee_ratio_bps(&env, bps);
+        events::emit_fee_params_changed_event(&env, previous, fees::fee_params(&env), &admin);
+    }
+
+    /// Get the total fee to notional cap (`None` means unlimited)
+    pub fn get_max_fee_ratio_bps(env: Env) -> Option<u32> {
+        storage::get_max_fee_ratio_bps(&env)
+    }
+
+    /// Set the maximum memo size and proof length accepted by settle_trade
+    /// Only admin can call this
+    pub fn set_instruction_limits(env: Env, limits: InstructionLimits) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::get_instruction_limits(&env);
+        storage::set_instruction_limits(&env, &limits);
+        events::emit_instruction_limits_event(&env, &previous, &limits, &admin);
+    }
+
+    /// Get the current instruction size limits
+    pub fn get_instruction_limits(env: Env) -> InstructionLimits {
+        storage::get_instruction_limits(&env)
+    }
+
+    /// Deposit assets into the contract vault
+    /// User must approve the contract to transfer tokens before calling this
+    pub fn deposit(env: Env, user: Address, token: Address, amount: i128) {
+        storage::extend_instance_ttl(&env);
+        user.require_auth();
+        check_positive_amount(amount);
+
+        // Verify token is supported and not paused
+        check_supported_asset(&env, &token);
+        check_asset_not_paused(&env, &token);
+        check_deposit_cap(&env, &user, &token, amount);
+
+        // Transfer tokens from user to contract, crediting what arrived
+        let received = receive_tokens(&env, &user, &token, amount);
+
+        // Update user balance in vault
+        let new_balance = storage::add_balance(&env, &user, &token, received);
+        storage::record_net_flows(&env, &user, &[(&token, received)]);
+        storage::record_deposit(&env, &user, &token, received);
+
+        events::emit_deposit_event(&env, &user, &token, received, new_balance);
+    }
+
+    /// Cap the vault balance any user's deposits may reach in an asset
+    /// `None` removes the cap (unlimited). Settlement proceeds are not capped.
+    /// Only admin can call this
+    pub fn set_deposit_cap(env: Env, asset: Address, cap: Option<i128>) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        check_supported_asset(&env, &asset);
+        if cap.is_some_and(|cap| cap < 0) {
+            panic!("Deposit cap must not be negative");
+        }
+        let previous = storage::get_deposit_cap(&env, &asset);
+        storage::set_deposit_cap(&env, &asset, cap);
+        events::emit_deposit_cap_changed_event(&env, &asset, None, (previous, cap), &admin);
+    }
+
+    /// Get an asset's deposit cap (`None` = unlimited)
+    pub fn get_deposit_cap(env: Env, asset: Address) -> Option<i128> {
+        storage::get_deposit_cap(&env, &asset)
+    }
+
+    /// Give one user a deposit cap in an asset that replaces the asset-wide
+    /// cap, e.g. for a compliance tier. `None` removes it.
+    /// Only admin can call this
+    pub fn set_user_deposit_cap(env: Env, user: Address, asset: Address, cap: Option<i128>) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        check_supported_asset(&env, &asset);
+        if cap.is_some_and(|cap| cap < 0) {
+            panic!("Deposit cap must not be negative");
+        }
+        let previous = storage::get_user_deposit_cap(&env, &user, &asset);
+        storage::set_user_deposit_cap(&env, &user, &asset, cap);
+        events::emit_deposit_cap_changed_event(&env, &asset, Some(user), (previous, cap), &admin);
+    }
+
+    /// Get a user's own deposit cap in an asset, if one is set
+    pub fn get_user_deposit_cap(env: Env, user: Address, asset: Address) -> Option<i128> {
+        storage::get_user_deposit_cap(&env, &user, &asset)
+    }
+
+    /// Deposit several assets into the contract vault in one call
+    /// Every (token, amount) entry is validated before any transfer happens,
+    /// so an unsupported token or non-positive amount reverts the whole batch
+    pub fn deposit_many(env: Env, user: Address, deposits: Vec<(Address, i128)>) {
+        storage::extend_instance_ttl(&env);
+        user.require_auth();
+
+        let mut totals: Map<Address, i128> = Map::new(&env);
+        for (token, amount) in deposits.iter() {
+            check_positive_amount(amount);
+            check_supported_asset(&env, &token);
+            check_asset_not_paused(&env, &token);
+            totals.set(token.clone(), totals.get(token).unwrap_or(0) + amount);
+        }
+        for (token, total) in totals.iter() {
+            check_deposit_cap(&env, &user, &token, total);
+        }
+
+        for (token, amount) in deposits.iter() {
+            let received = receive_tokens(&env, &user, &token, amount);
+
+            let new_balance = storage::add_balance(&env, &user, &token, received);
+            storage::record_net_flows(&env, &user, &[(&token, received)]);
+            storage::record_deposit(&env, &user, &token, received);
+
+            events::emit_deposit_event(&env, &user, &token, received, new_balance);
+        }
+    }
+
+    /// Lock part of the user's vault balance behind a resting order, so it
+    /// cannot be withdrawn until settled or unlocked. The user authorizes,
+    /// and may lock at most their available balance
+    pub fn lock_balance(env: Env, user: Address, asset: Address, amount: i128) {
+        user.require_auth();
+        check_positive_amount(amount);
+        if storage::get_available_balance(&env, &user, &asset) < amount {
+            panic!("Insufficient available balance");
+        }
+        let locked = storage::adjust_locked_balance(&env, &user, &asset, amount);
+        events::emit_lock_changed_event(&env, &user, &asset, amount, locked);
+    }
+
+    /// Lock part of the user's vault balance behind a resting order that
+    /// expires at `expires_at`. It counts towards the locked balance like
+    /// lock_balance, and once expired anyone may hand it back with
+    /// unlock_expired. `lock_id` names the lock and is used once
+    pub fn lock_balance_until(
+        env: Env,
+        user: Address,
+        asset: Address,
+        lock_id: BytesN<32>,
+        amount: i128,
+        expires_at: u64,
+    ) {
+        user.require_auth();
+        check_positive_amount(amount);
+        if expires_at <= env.ledger().timestamp() {
+            panic!("Lock expiry must be in the future");
+        }
+        if storage::get_balance_lock(&env, &lock_id).is_some() {
+            panic!("Lock id already used");
+        }
+        if storage::get_available_balance(&env, &user, &asset) < amount {
+            panic!("Insufficient available balance");
+        }
+        let lock = BalanceLock { user: user.clone(), asset: asset.clone(), amount, remaining: amount, expires_at };
+        storage::set_balance_lock(&env, &lock_id, Some(lock));
+        let locked = storage::adjust_locked_balance(&env, &user, &asset, amount);
+        events::emit_lock_changed_event(&env, &user, &asset, amount, locked);
+    }
+
+    /// Return what is left of an expired lock to the user's free balance.
+    /// Anyone may call this once the ledger reaches the lock's expiry.
+    /// Settlements and unlocks that draw on the locked balance use up any
+    /// lock_balance amount first and then the timed locks, soonest expiry
+    /// first, so only the lock's own remaining amount is released
+    pub fn unlock_expired(env: Env, user: Address, asset: Address, lock_id: BytesN<32>) {
+        let lock = storage::get_balance_lock(&env, &lock_id).expect("Lock not found");
+        if lock.user != user || lock.asset != asset {
+            panic!("Lock belongs to another balance");
+        }
+        if env.ledger().timestamp() < lock.expires_at {
+            panic!("Lock has not expired");
+        }
+        storage::set_balance_lock(&env, &lock_id, None);
+        let released = lock.remaining;
+        if released > 0 {
+            let locked = storage::adjust_locked_balance(&env, &user, &asset, -released);
+            events::emit_lock_changed_event(&env, &user, &asset, -released, locked);
+        }
+    }
+
+    /// Get a lock placed with lock_balance_until, if it has not been reclaimed
+    pub fn get_balance_lock(env: Env, lock_id: BytesN<32>) -> Option<BalanceLock> {
+        storage::get_balance_lock(&env, &lock_id)
+    }
+
+    /// Release locked balance once the order it backed is cancelled or
+    /// expires. Only the matching engine can call this
+    pub fn unlock_balance(env: Env, user: Address, asset: Address, amount: i128) {
+        require_matching_engine(&env);
+        check_positive_amount(amount);
+        let locked = storage::get_locked_balance(&env, &user, &asset);
+        if amount > locked {
+            panic!("Unlock exceeds locked balance: {} > {}", amount, locked);
+        }
+        let locked = storage::adjust_locked_balance(&env, &user, &asset, -amount);
+        events::emit_lock_changed_event(&env, &user, &asset, -amount, locked);
+    }
+
+    /// Release all of a user's locked balance in an asset, for when the
+    /// matching engine is unset or was replaced before unlocking it
+    /// Only admin can call this
+    pub fn release_locked_balance(env: Env, user: Address, asset: Address) {
+        storage::get_admin(&env).require_auth();
+        let released = storage::get_locked_balance(&env, &user, &asset);
+        if released == 0 {
+            panic!("Nothing locked");
+        }
+        let locked = storage::adjust_locked_balance(&env, &user, &asset, -released);
+        events::emit_lock_changed_event(&env, &user, &asset, -released, locked);
+    }
+
+    /// Withdraw assets from the contract vault
+    pub fn withdraw(env: Env, user: Address, token: Address, amount: i128) {
+        Self::withdraw_to(env, user.clone(), user, token, amount);
+    }
+
+    /// Withdraw assets from the user's vault balance to another address,
+    /// such as a custody wallet. The user authorizes and is debited
+    pub fn withdraw_to(env: Env, user: Address, destination: Address, token: Address, amount: i128) {
+        storage::extend_instance_ttl(&env);
+        user.require_auth();
+        check_positive_amount(amount);
+
+        // Check user has sufficient balance outside any lock
+        if storage::get_available_balance(&env, &user, &token) < amount {
+            panic!("Insufficient balance");
+        }
+
+        // Update user balance in vault
+        let new_balance = storage::subtract_balance(&env, &user, &token, amount);
+        storage::record_net_flows(&env, &user, &[(&token, -amount)]);
+        storage::record_withdrawal(&env, &user, &token, amount);
+
+        // Transfer tokens from contract to the destination
+        use soroban_sdk::token::TokenClient;
+        let token_client = TokenClient::new(&env, &token);
+        token_client.transfer(&env.current_contract_address(), &destination, &amount);
+
+        events::emit_withdraw_event(&env, &user, &destination, &token, amount, new_balance);
+    }
+
+    /// Limit how much of the user's vault balance in an asset the matching
+    /// engine may settle on their behalf. Each settlement draws the amount
+    /// (including fees) taken from the user; trades beyond it are rejected
+    pub fn set_engine_allowance(env: Env, user: Address, asset: Address, amount: i128) {
+        user.require_auth();
+        if amount < 0 {
+            panic!("Allowance must not be negative: {}", amount);
+        }
+        check_supported_asset(&env, &asset);
+        storage::set_engine_allowance(&env, &user, &asset, amount);
+    }
+
+    /// Reconcile a user's stored balance against the running sum of their
+    /// deposits, withdrawals and settlement deltas. A mismatch points at
+    /// storage corruption or a bookkeeping bug. Balances written before the
+    /// ledger existed have no recorded movements and report false until the
+    /// admin seeds them with seed_net_flows
+    pub fn verify_user_consistency(env: Env, user: Address, asset: Address) -> bool {
+        storage::get_balance(&env, &user, &asset) == storage::get_net_flow(&env, &user, &asset)
+    }
+
+    /// Get the remaining engine allowance (`None` means unlimited)
+    pub fn get_engine_allowance(env: Env, user: Address, asset: Address) -> Option<i128> {
+        storage::get_engine_allowance(&env, &user, &asset)
+    }
+
+    /// Extend the contract instance TTL
+    /// Anyone can call this to keep an idle contract's configuration alive
+    pub fn bump_instance(env: Env) {
+        storage::extend_instance_ttl(&env);
+    }
+
+    /// Get user balance for a specific asset
+    pub fn get_balance(env: Env, user: Address, token: Address) -> i128 {
+        storage::get_balance(&env, &user, &token)
+    }
+
+    /// Get the part of a user's balance locked behind resting orders
+    pub fn get_locked_balance(env: Env, user: Address, token: Address) -> i128 {
+        storage::get_locked_balance(&env, &user, &token)
+    }
+
+    /// Get the part of a user's balance they may withdraw now: the balance
+    /// less its locked part, never negative
+    pub fn get_available_balance(env: Env, user: Address, token: Address) -> i128 {
+        storage::get_available_balance(&env, &user, &token)
+    }
+
+    /// Get a user's balance in one asset broken into its free and locked
+    /// parts, alongside the total get_balance reports
+    pub fn get_balance_detail(env: Env, user: Address, asset: Address) -> BalanceDetail {
+        let total = storage::get_balance(&env, &user, &asset);
+        let locked = storage::get_locked_balance(&env, &user, &asset);
+        BalanceDetail {
+            free: (total - locked).max(0),
+            locked,
+            total,
+        }
+    }
+
+    /// Get a user's balance in every supported asset, in configuration order
+    /// Zero balances are included, so every asset always appears
+    pub fn get_balances(env: Env, user: Address) -> Vec<AssetBalance> {
+        let mut balances = Vec::new(&env);
+        for asset in storage::get_supported_assets(&env) {
+            balances.push_back(AssetBalance {
+                amount: storage::get_balance(&env, &user, &asset),
+                locked: storage::get_locked_balance(&env, &user, &asset),
+                asset,
+            });
+        }
+        balances
+    }
+
+    /// Get the total vault balance users hold in an asset
+    /// Protocol accounts (admin/fee recipient) are excluded
+    pub fn get_open_interest(env: Env, asset: Address) -> i128 {
+        storage::get_open_interest(&env, &asset)
+    }
+
+    /// Get the sum of every vault balance in an asset, the admin's included
+    /// Kept as a running counter, so the cost does not grow with the user count
+    pub fn get_vault_total(env: Env, asset: Address) -> i128 {
+        storage::get_vault_total(&env, &asset)
+    }
+
+    /// Get the vault total together with the segregated fee, insurance and
+    /// referral balances; their sum is what the vault's token balance must cover
+    pub fn get_vault_totals(env: Env, asset: Address) -> VaultTotals {
+        VaultTotals {
+            user_balances: storage::get_vault_total(&env, &asset),
+            fee_balance: storage::get_fee_balance(&env, &asset),
+            insurance_balance: storage::get_insurance_balance(&env, &asset),
+            referral_balance: storage::get_referral_total(&env, &asset),
+            asset,
+        }
+    }
+
+    /// Get supported Asset A
+    pub fn get_asset_a(env: Env) -> Address {
+        storage::get_asset_a(&env)
+    }
+
+    /// Get supported Asset B
+    pub fn get_asset_b(env: Env) -> Address {
+        storage::get_asset_b(&env)
+    }
+
+    /// Preview the (fee_base, fee_quote) settle_trade would charge for a trade
+    /// between `buy_user` and `sell_user`. The seller pays fee_base and the
+    /// buyer fee_quote, at the taker or maker rate according to `taker_side`
+    /// and after each side's volume-tier discount, exactly as settlement
+    /// computes them; in quote-only fee mode both sides' fees are in fee_quote.
+    /// Returns (0, 0) while no on-chain rates are configured, since fees then
+    /// come from the matching engine
+    #[allow(clippy::too_many_arguments)]
+    pub fn preview_fees(
+        env: Env,
+        buy_user: Address,
+        sell_user: Address,
+        base_asset: Address,
+        quote_asset: Address,
+        base_amount: i128,
+        quote_amount: i128,
+        taker_side: Side,
+    ) -> (i128, i128) {
+        check_supported_asset(&env, &base_asset);
+        check_supported_asset(&env, &quote_asset);
+        check_positive_amount(base_amount);
+        check_positive_amount(quote_amount);
+        let parties = (&buy_user, &sell_user);
+        fees::computed_fees(&env, parties, &base_asset, &quote_asset, base_amount, quote_amount, taker_side)
+            .unwrap_or((0, 0))
+    }
+
+    /// Protocol fees accrued in an asset and not yet paid out
+    /// These are never part of any user's (or the admin's) vault balance
+    pub fn get_fee_balance(env: Env, asset: Address) -> i128 {
+        storage::get_fee_balance(&env, &asset)
+    }
+
+    /// Lifetime fees collected in an asset, before maker rebates
+    pub fn get_fees_collected(env: Env, asset: Address) -> i128 {
+        storage::get_fees_collected(&env, &asset)
+    }
+
+    /// Lifetime fees collected in an asset, net of the maker rebates paid
+    /// out of them
+    pub fn get_net_fees_collected(env: Env, asset: Address) -> i128 {
+        storage::get_fees_collected(&env, &asset) - storage::get_maker_rebates(&env, &asset)
+    }
+
+    /// Lifetime fees collected in an asset (before maker rebates, as
+    /// get_fees_collected), with how much has been claimed
+    /// (including insurance payouts) and how much is still held across the
+    /// fee, insurance and referral balances. Negative maker fees and keeper
+    /// rewards are paid from the unclaimed balance without counting as claimed
+    pub fn get_collected_fees(env: Env, asset: Address) -> FeeTotals {
+        FeeTotals {
+            collected: storage::get_fees_collected(&env, &asset),
+            claimed: storage::get_fees_claimed(&env, &asset),
+            unclaimed: storage::get_fee_balance(&env, &asset)
+                + storage::get_insurance_balance(&env, &asset)
+                + storage::get_referral_total(&env, &asset),
+        }
+    }
+
+    /// Fees collected in an asset over the days (UTC, by ledger timestamp)
+    /// from `from_ts` through `to_ts` inclusive, spanning at most 366 days.
+    /// Like get_fees_collected these are before maker rebates, so the days
+    /// add up to the lifetime total
+    pub fn get_fees_in_range(env: Env, asset: Address, from_ts: u64, to_ts: u64) -> i128 {
+        let (from_day, to_day) = day_range(from_ts, to_ts);
+        (from_day..=to_day)
+            .map(|day| storage::get_daily_fees(&env, &asset, day))
+            .sum()
+    }
+
+    /// Settlements settled at a ledger timestamp in `from_ts..=to_ts`, most
+    /// recent sequence first, for compliance exports. Pages through the
+    /// per-day settlement index, which keeps each entry's timestamp, so only
+    /// returned records are loaded: `offset` skips that many matches and at
+    /// most `limit` (capped at MAX_RANGE_RESULTS) are returned. The window
+    /// may span at most 366 days
+    pub fn get_settlements_in_range(
+        env: Env,
+        from_ts: u64,
+        to_ts: u64,
+        offset: u32,
+        limit: u32,
+    ) -> Vec<SettlementRecord> {
+        let (from_day, to_day) = day_range(from_ts, to_ts);
+        let mut records = Vec::new(&env);
+        let limit = limit.min(MAX_RANGE_RESULTS);
+        let mut skipped = 0;
+        for day in (from_day..=to_day).rev() {
+            let (pages, head) = storage::get_day_settlements(&env, day);
+            let mut page = head;
+            for page_number in (0..=pages).rev() {
+                for (sequence, timestamp) in page.iter().rev() {
+                    if records.len() == limit {
+                        return records;
+                    }
+                    if timestamp < from_ts || timestamp > to_ts {
+                        continue;
+                    }
+                    if skipped < offset {
+                        skipped += 1;
+                    } else if let Some(record) = storage::get_settlement_by_sequence(&env, sequence) {
+                        records.push_back(record);
+                    }
+                }
+                if page_number > 0 {
+                    page = storage::get_day_settlement_page(&env, day, page_number - 1);
+                }
+            }
+        }
+        records
+    }
+
+    /// Get the admin address
+    pub fn get_admin(env: Env) -> Option<Address> {
+        storage::try_get_admin(&env)
+    }
+
+    /// Get the authorized matching engine, if one is set
+    pub fn get_matching_engine(env: Env) -> Option<Address> {
+        storage::get_matching_engine(&env)
+    }
+
+    /// Get every address authorized to settle as a matching engine. The
+    /// contract authorizes a single engine, so this holds at most one
+    pub fn get_matching_engines(env: Env) -> Vec<Address> {
+        let mut engines = Vec::new(&env);
+        if let Some(matching_engine) = storage::get_matching_engine(&env) {
+            engines.push_back(matching_engine);
+        }
+        engines
+    }
+
+    /// Whether `address` is authorized to settle as a matching engine, so an
+    /// engine can check itself before submitting
+    pub fn is_matching_engine(env: Env, address: Address) -> bool {
+        storage::get_matching_engine(&env) == Some(address)
+    }
+
+    /// Get the admin, engine, assets, pause flags, modes and fee settings in
+    /// a single call
+    pub fn get_config(env: Env) -> ContractConfig {
+        let asset_a = storage::get_asset_a(&env);
+        let asset_b = storage::get_asset_b(&env);
+        let fee_rates = storage::get_fee_rates(&env);
+        ContractConfig {
+            interface_version: INTERFACE_VERSION,
+            admin: storage::get_admin(&env),
+            matching_engine: storage::get_matching_engine(&env),
+            fee_recipient: Self::get_fee_recipient(env.clone()),
+            asset_a_paused: storage::is_asset_paused(&env, &asset_a),
+            asset_b_paused: storage::is_asset_paused(&env, &asset_b),
+            asset_a,
+            asset_b,
+            settlement_mode: storage::get_settlement_mode(&env),
+            fee_mode: storage::get_fee_mode(&env),
+            maker_fee_bps: fee_rates.as_ref().map(|rates| rates.maker_bps),
+            taker_fee_bps: fee_rates.map(|rates| rates.taker_bps),
+            max_fee_bps: storage::get_max_fee_bps(&env),
+            maker_rebate_bps: storage::get_maker_rebate_bps(&env),
+        }
+    }
+
+    /// Get the fixed-point scale used for execution prices
+    pub fn get_price_scale(env: Env) -> i128 {
+        storage::get_price_scale(&env)
+    }
+
+    /// Get the canonical hash of an instruction, for clients to check their
+    /// own serialization against before signing
+    pub fn get_instruction_hash(env: Env, instruction: SettlementInstruction) -> BytesN<32> {
+        instruction_hash(&env, &instruction)
+    }
+
+    /// Settle a trade
+    /// Needs the authorization set_auth_mode selects (by default the
+    /// matching engine's)
+    /// On success the outcome carries the stored settlement record and both
+    /// parties' post-trade vault balances; a balance or allowance rejection
+    /// carries the account, asset and amounts that fell short
+    pub fn settle_trade(env: Env, instruction: SettlementInstruction) -> SettlementOutcome {
+        Self::settle(env, instruction, None, Submission::Engine)
+    }
+
+    /// Dry-run settle_trade: run every check it makes, in the same code, and
+    /// return the result it would give now along with the execution price,
+    /// fees and both sides' post-trade vault balances. Writes nothing and
+    /// emits nothing, and needs no authorization. In immediate-transfer mode
+    /// the outgoing token transfers are not attempted
+    pub fn preview_settle(env: Env, instruction: SettlementInstruction) -> SettlementPreview {
+        let mut plan = match Self::plan_settlement(&env, &instruction, None, Submission::Preview) {
+            Ok(plan) => plan,
+            Err(outcome) => {
+                return SettlementPreview {
+                    result: outcome.result,
+                    execution_price: 0,
+                    fee_base: 0,
+                    fee_quote: 0,
+                    maker_rebate: 0,
+                    buyer_base_balance: 0,
+                    buyer_quote_balance: 0,
+                    seller_base_balance: 0,
+                    seller_quote_balance: 0,
+                }
+            }
+        };
+        let (buyer, seller) = (&instruction.buy_user, &instruction.sell_user);
+        let (base, quote) = (&instruction.base_asset, &instruction.quote_asset);
+        SettlementPreview {
+            result: SettlementResult::Success,
+            execution_price: plan.price,
+            fee_base: plan.trade_fees.fee_base(),
+            fee_quote: plan.trade_fees.fee_quote(),
+            maker_rebate: plan.rebate,
+            buyer_base_balance: plan.balances.get(&env, buyer, base),
+            buyer_quote_balance: plan.balances.get(&env, buyer, quote),
+            seller_base_balance: plan.balances.get(&env, seller, base),
+            seller_quote_balance: plan.balances.get(&env, seller, quote),
+        }
+    }
+
+    /// Preview the (quote, base) settle_trade would take from the buyer and
+    /// the seller for an instruction: each side's amount plus the fee it
+    /// pays in that asset, by the same fee logic. Balances are not checked
+    /// and no state changes; a separate `fee_asset` fee is not included
+    pub fn preview_required(env: Env, instruction: SettlementInstruction) -> (i128, i128) {
+        let trade_fees = fees::settlement_fees(&env, &instruction, storage::get_fee_mode(&env));
+        trade_fees.required_amounts(&instruction)
+    }
+
+    /// Settle an OTC trade negotiated directly between its counterparties
+    /// Both `buy_user` and `sell_user` must authorize it in place of the
+    /// matching engine, which need not be set. Otherwise it settles exactly
+    /// like settle_trade, except that engine allowances do not apply
+    pub fn settle_p2p(env: Env, instruction: SettlementInstruction) -> SettlementOutcome {
+        Self::settle(env, instruction, None, Submission::PeerToPeer)
+    }
+
+    /// Settle a batch of trades submitted by a keeper, returning each trade's
+    /// result in order. Every instruction still needs the matching engine's
+    /// authorization, so keepers can only relay engine-signed settlements,
+    /// and batches are refused under AuthMode::BothParties.
+    /// `keeper` must authorize and is credited the keeper reward for each
+    /// successful settlement, out of the protocol fee balance (skipped in
+    /// any asset whose fee balance cannot cover it). A BatchSettledEvent
+    /// summarizes the settled trades, after their own SettlementEvents
+    /// unless batch events are set to summary only
+    pub fn settle_trades(env: Env, instructions: Vec<SettlementInstruction>, keeper: Address) -> Vec<SettlementResult> {
+        keeper.require_auth();
+        require_engine_submission(&env);
+        let mut results = Vec::new(&env);
+        let mut trade_ids = Vec::new(&env);
+        let (mut total_base, mut total_quote) = (0i128, 0i128);
+        for instruction in instructions.iter() {
+            let (trade_id, base_amount, quote_amount) =
+                (instruction.trade_id.clone(), instruction.base_amount, instruction.quote_amount);
+            let result = Self::settle(env.clone(), instruction, None, Submission::Batch).result;
+            if result == SettlementResult::Success {
+                trade_ids.push_back(trade_id);
+                total_base += base_amount;
+                total_quote += quote_amount;
+            }
+            results.push_back(result);
+        }
+        fees::pay_keeper_reward(&env, &keeper, trade_ids.len());
+        events::emit_batch_settled_event(&env, &trade_ids, total_base, total_quote);
+        results
+    }
+
+    /// Queue a settlement for a later process_queue call instead of settling
+    /// it now. Only the matching engine can call this, and not under
+    /// AuthMode::BothParties. Everything settle
+    /// would panic on in the instruction itself (amounts, a negative fee
+    /// amount) and its size are checked up front, and every other problem
+    /// is a rejected result when it settles. Only an immediate-transfer
+    /// payout the token refuses after the other leg has paid can still
+    /// abort process_queue; the admin can then remove it with drop_queued
+    pub fn enqueue_settlement(env: Env, instruction: SettlementInstruction) {
+        storage::extend_instance_ttl(&env);
+        require_engine_submission(&env);
+        check_positive_amount(instruction.base_amount);
+        check_positive_amount(instruction.quote_amount);
+        if instruction.fee_asset.is_some() && instruction.fee_amount < 0 {
+            panic!("Fee amount must not be negative: {}", instruction.fee_amount);
+        }
+        let limits = storage::get_instruction_limits(&env);
+        if instruction.memo.len() > limits.max_memo_bytes || instruction.proof.len() > limits.max_proof_len {
+            panic!("Instruction too large");
+        }
+        let engine = storage::get_matching_engine(&env).unwrap();
+        storage::push_queued_settlement(&env, &QueuedInstruction { instruction, engine });
+    }
+
+    /// Settle up to `max` queued instructions, oldest first, returning each
+    /// one's result in order. Anyone (typically a keeper) can call this, as
+    /// the matching engine authorized each instruction when queueing it.
+    /// An instruction queued by an engine that has since been replaced or
+    /// unset, or processed while AuthMode::BothParties is set, returns
+    /// `InvalidSignature` without settling. Every processed
+    /// instruction leaves the queue, whether it settled or was rejected
+    pub fn process_queue(env: Env, max: u32) -> Vec<SettlementResult> {
+        let mut results = Vec::new(&env);
+        while results.len() < max {
+            let Some(queued) = storage::pop_queued_settlement(&env) else {
+                break;
+            };
+            if storage::get_matching_engine(&env) != Some(queued.engine)
+                || storage::get_auth_mode(&env) == AuthMode::BothParties
+            {
+                let outcome = SettlementOutcome::rejected(SettlementResult::InvalidSignature);
+                events::emit_settlement_rejected_event(&env, &queued.instruction, &outcome);
+                results.push_back(outcome.result);
+                continue;
+            }
+            results.push_back(Self::settle(env.clone(), queued.instruction, None, Submission::Queued).result);
+        }
+        results
+    }
+
+    /// Remove the instruction at the front of the settlement queue without
+    /// settling it, for an entry that keeps aborting process_queue. Returns
+    /// the removed instruction. Only admin can call this
+    pub fn drop_queued(env: Env) -> SettlementInstruction {
+        storage::get_admin(&env).require_auth();
+        storage::pop_queued_settlement(&env).expect("Settlement queue is empty").instruction
+    }
+
+    /// Number of settlements waiting for process_queue
+    pub fn get_queue_len(env: Env) -> u64 {
+        storage::get_queue_len(&env)
+    }
+
+    /// Emit only the BatchSettledEvent for settle_trades batches, dropping
+    /// their per-trade SettlementEvents to save cost. Only admin can call this
+    pub fn set_batch_summary_only(env: Env, summary_only: bool) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::is_batch_summary_only(&env);
+        storage::set_batch_summary_only(&env, summary_only);
+        events::emit_setting_changed_event(&env, "batch_summary_only", previous.into(), summary_only.into(), &admin);
+    }
+
+    /// Whether settle_trades batches emit only their summary event
+    pub fn is_batch_summary_only(env: Env) -> bool {
+        storage::is_batch_summary_only(&env)
+    }
+
+    /// Settle a trade at an execution price and quantity supplied by the
+    /// matching engine instead of derived from the amounts, e.g. to apply its
+    /// own rounding policy. `execution_quantity` may not exceed the base
+    /// amount, and the notional it implies at `execution_price` must match the
+    /// quote paid for that quantity to within EXECUTION_TOLERANCE_BPS (at
+    /// least 1 unit);
+    /// otherwise returns `InconsistentExecution`. Price bounds apply to the
+    /// supplied price
+    pub fn settle_trade_priced(
+        env: Env,
+        instruction: SettlementInstruction,
+        execution_price: i128,
+        execution_quantity: i128,
+    ) -> SettlementOutcome {
+        Self::settle(env, instruction, Some((execution_price, execution_quantity)), Submission::Engine)
+    }
+
+    /// Shared settlement path; `execution` carries an engine-supplied
+    /// (price, quantity) in place of the ones derived from the amounts.
+    /// Every check lives in plan_settlement, shared with preview_settle.
+    /// Batched trades come from settle_trades, which authorizes the matching
+    /// engine once up front (an address can authorize only once per call) and
+    /// may replace their events with a single summary
+    fn settle(
+        env: Env,
+        instruction: SettlementInstruction,
+        execution: Option<(i128, i128)>,
+        submission: Submission,
+    ) -> SettlementOutcome {
+        trace!(&env, "settle_trade: Starting settlement");
+        storage::extend_instance_ttl(&env);
+
+        let plan = match Self::plan_settlement(&env, &instruction, execution, submission) {
+            Ok(plan) => plan,
+            Err(outcome) => {
+                events::emit_settlement_rejected_event(&env, &instruction, &outcome);
+                return outcome;
+            }
+        };
+        let SettlementPlan {
+            price,
+            quantity,
+            mode,
+            fee_mode,
+            trade_fees,
+            rebate,
+            required_quote,
+            required_base,
+            seller_quote,
+            fee_payer,
+            token_fee,
+            protocol_rebates: (protocol_rebate_base, protocol_rebate_quote),
+            allowances: (buy_allowance, sell_allowance, fee_allowance),
+            maker_rebates: (buyer_rebate, seller_rebate),
+            buyer_rebate_asset,
+            mut balances,
+        } = plan;
+        let base = &instruction.base_asset;
+        let quote = &instruction.quote_asset;
+
+        // In immediate-transfer mode, push proceeds to wallets before any
+        // balance is written, so a rejected first transfer leaves no trace.
+        // Both legs complete or neither does: once the base leg has paid out
+        // it cannot be pulled back, so a failed quote leg aborts the whole
+        // invocation with a TransferFailed error, reverting the base leg too
+        if mode == SettlementMode::ImmediateTransfer {
+            trace!(&env, "settle_trade: Pushing proceeds to counterparty wallets");
+            if !try_transfer_out(&env, &instruction.base_asset, &instruction.buy_user, instruction.base_amount) {
+                log!(&env, "settle_trade: ERROR - Base transfer to buyer failed");
+                let outcome = SettlementOutcome::rejected(SettlementResult::TransferFailed);
+                events::emit_settlement_rejected_event(&env, &instruction, &outcome);
+                return outcome;
+            }
+            if !try_transfer_out(&env, &instruction.quote_asset, &instruction.sell_user, seller_quote) {
+                log!(&env, "settle_trade: ERROR - Quote transfer to seller failed");
+                panic_with_error!(&env, SettlementError::TransferFailed);
+            }
+        }
+
+        // Write each touched balance exactly once, and mirror every movement
+        // in the net-flow ledger checked by verify_user_consistency and in
+        // each side's activity journal
+        let balance_delta = balances.commit(&env);
+        let (base_proceeds, quote_proceeds) = match mode {
+            SettlementMode::Internal => (instruction.base_amount, seller_quote),
+            SettlementMode::ImmediateTransfer => (0, 0),
+        };
+        // The taker's fee in a separate fee token joins its own flows
+        let fee_flow = |user: &Address| match token_fee {
+            Some((fee_asset, fee_amount)) if user == fee_payer => (fee_asset, -fee_amount),
+            _ => (quote, 0),
+        };
+        let buyer_flows = [
+            (quote, protocol_rebate_quote - required_quote),
+            (base, base_proceeds),
+            (buyer_rebate_asset, buyer_rebate),
+            fee_flow(&instruction.buy_user),
+        ];
+        let seller_flows = [
+            (base, protocol_rebate_base - required_base),
+            (quote, quote_proceeds + seller_rebate),
+            fee_flow(&instruction.sell_user),
+        ];
+        let trade_id = Some(&instruction.trade_id);
+        storage::record_net_flows(&env, &instruction.buy_user, &buyer_flows);
+        storage::record_net_flows(&env, &instruction.sell_user, &seller_flows);
+        storage::record_activity(&env, &instruction.buy_user, ActivityKind::Buy, trade_id, &buyer_flows);
+        storage::record_activity(&env, &instruction.sell_user, ActivityKind::Sell, trade_id, &seller_flows);
+        // Debits draw on locked funds first, so no lock outlives its balance
+        consume_lock(&env, &instruction.buy_user, quote, required_quote);
+        consume_lock(&env, &instruction.sell_user, base, required_base);
+        if let Some((fee_asset, fee_amount)) = token_fee {
+            consume_lock(&env, fee_payer, fee_asset, fee_amount);
+        }
+
+        // 6. Collect fees into the insurance, referrer and protocol fee
+        // balances, kept apart from every vault balance (including the admin's)
+        // The maker rebate has already been paid out of the taker's fee, and
+        // a negative maker fee comes out of the protocol fee balance
+        trace!(&env, "settle_trade: Step 6 - Collecting fees");
+        if protocol_rebate_base > 0 {
+            storage::debit_fee(&env, base, protocol_rebate_base);
+        }
+        if protocol_rebate_quote > 0 {
+            storage::debit_fee(&env, quote, protocol_rebate_quote);
+        }
+        // Each side's fee, net of any maker rebate paid out of it, is split
+        // with that side's own referrer
+        let seller_fee_asset = match fee_mode {
+            FeeMode::PerLeg => base,
+            FeeMode::QuoteOnly => quote,
+        };
+        let buyer_fee = trade_fees.buyer_quote - seller_rebate;
+        let seller_fee = trade_fees.seller_base + trade_fees.seller_quote - buyer_rebate;
+        if buyer_fee > 0 || seller_fee > 0 {
+            let split = storage::get_fee_split(&env);
+            if buyer_fee > 0 {
+                trace!(&env, "settle_trade: Collecting buyer fee");
+                let referrer = fees::referrer_of(&env, &instruction.buy_user, &split);
+                fees::collect_fee(&env, quote, buyer_fee, &split, referrer.as_ref());
+            }
+            if seller_fee > 0 {
+                trace!(&env, "settle_trade: Collecting seller fee");
+                let referrer = fees::referrer_of(&env, &instruction.sell_user, &split);
+                fees::collect_fee(&env, seller_fee_asset, seller_fee, &split, referrer.as_ref());
+            }
+            let seller_quote_fee = if fee_mode == FeeMode::QuoteOnly { seller_fee } else { 0 };
+            fees::pay_referral_rebate(&env, &instruction, &[buyer_fee, seller_quote_fee]);
+            trace!(&env, "settle_trade: Fees collected");
+        } else {
+            trace!(&env, "settle_trade: No fees to collect");
+        }
+        if let Some((fee_asset, fee_amount)) = token_fee {
+            trace!(&env, "settle_trade: Collecting taker fee in fee asset");
+            let split = storage::get_fee_split(&env);
+            let referrer = fees::referrer_of(&env, fee_payer, &split);
+            fees::collect_fee(&env, fee_asset, fee_amount, &split, referrer.as_ref());
+            storage::add_daily_fees(&env, &[(fee_asset, fee_amount)]);
+            if let Some(allowance) = fee_allowance {
+                storage::update_engine_allowance(&env, fee_payer, fee_asset, allowance - fee_amount);
+            }
+        }
+        if let Some(allowance) = buy_allowance {
+            storage::update_engine_allowance(&env, &instruction.buy_user, &instruction.quote_asset, allowance - required_quote);
+        }
+        if let Some(allowance) = sell_allowance {
+            storage::update_engine_allowance(&env, &instruction.sell_user, &instruction.base_asset, allowance - required_base);
+        }
+
+        // The collected totals, lifetime and daily, count each taker fee in
+        // full, before the rebate paid out of it
+        if seller_rebate > 0 {
+            storage::add_maker_rebate(&env, quote, seller_rebate);
+        }
+        if buyer_rebate > 0 {
+            storage::add_maker_rebate(&env, seller_fee_asset, buyer_rebate);
+        }
+        storage::add_daily_fees(
+            &env,
+            &[
+                (quote, buyer_fee.max(0) + seller_rebate.max(0)),
+                (seller_fee_asset, seller_fee.max(0) + buyer_rebate.max(0)),
+            ],
+        );
+
+        fees::record_volume(&env, &instruction);
+
+        // 7. Record settlement
+        trace!(&env, "settle_trade: Step 7 - Recording settlement");
+        let record = storage::record_settlement(
+            &env,
+            &instruction,
+            (price, quantity),
+            trade_fees.fee_base(),
+            trade_fees.fee_quote(),
+            rebate,
+            &balance_delta,
+        );
+        trace!(&env, "settle_trade: Settlement recorded");
+
+        let (buyer, seller) = (&instruction.buy_user, &instruction.sell_user);
+        let settled = SettledTrade {
+            buyer_base_balance: balances.get(&env, buyer, base),
+            buyer_quote_balance: balances.get(&env, buyer, quote),
+            seller_base_balance: balances.get(&env, seller, base),
+            seller_quote_balance: balances.get(&env, seller, quote),
+            record,
+        };
+
+        // 8. Emit events
+        trace!(&env, "settle_trade: Step 8 - Emitting events");
+        if !(submission == Submission::Batch && storage::is_batch_summary_only(&env)) {
+            let fee_recipient = storage::get_fee_recipient(&env).unwrap_or_else(|| storage::get_admin(&env));
+            events::emit_settlement_event(&env, &settled, &fee_recipient);
+        }
+        trace!(&env, "settle_trade: Events emitted");
+
+        trace!(&env, "settle_trade: Settlement completed successfully");
+        SettlementOutcome {
+            result: SettlementResult::Success,
+            detail: OutcomeDetail::Settled(settled),
+        }
+    }
+
+    /// Run every check settle_trade makes and work out the settlement's
+    /// price, fees and balance changes, without writing any state. The
+    /// balance changes are staged in the returned plan's batch; only
+    /// settle commits them. Authorization is checked as `submission` requires
+    #[allow(clippy::result_large_err)] // see OutcomeDetail
+    fn plan_settlement<'a>(
+        env: &Env,
+        instruction: &'a SettlementInstruction,
+        execution: Option<(i128, i128)>,
+        submission: Submission,
+    ) -> Result<SettlementPlan<'a>, SettlementOutcome> {
+        // Reject oversized payloads before reading any other state
+        let limits = storage::get_instruction_limits(env);
+        if instruction.memo.len() > limits.max_memo_bytes
+            || instruction.proof.len() > limits.max_proof_len
+        {
+            log!(env, "settle_trade: ERROR - Instruction too large");
+            return Err(SettlementOutcome::rejected(SettlementResult::InstructionTooLarge));
+        }
+
+        if instruction.expiry != 0 && env.ledger().timestamp() > instruction.expiry {
+            log!(env, "settle_trade: ERROR - Instruction expired");
+            return Err(SettlementOutcome::rejected(SettlementResult::Expired));
+        }
+
+        // Verify assets match supported assets
+        let asset_a = storage::get_asset_a(env);
+        let asset_b = storage::get_asset_b(env);
+        let base = &instruction.base_asset;
+        let quote = &instruction.quote_asset;
+
+        trace!(env, "settle_trade: Checking asset support");
+        if (base != &asset_a && base != &asset_b) || (quote != &asset_a && quote != &asset_b) {
+             log!(env, "settle_trade: ERROR - Unsupported asset in trade");
+             return Err(SettlementOutcome::rejected(SettlementResult::InvalidMatchingProof));
+        }
+
+        if storage::is_asset_paused(env, base) || storage::is_asset_paused(env, quote) {
+            log!(env, "settle_trade: ERROR - Asset paused");
+            return Err(SettlementOutcome::rejected(SettlementResult::AssetPaused));
+        }
+
+        // A separate fee token must be a supported third asset
+        if let Some(fee_asset) = &instruction.fee_asset {
+            if fee_asset == base || fee_asset == quote || !storage::get_supported_assets(env).contains(fee_asset) {
+                log!(env, "settle_trade: ERROR - Unsupported fee asset");
+                return Err(SettlementOutcome::rejected(SettlementResult::InvalidMatchingProof));
+            }
+            if storage::is_asset_paused(env, fee_asset) {
+                log!(env, "settle_trade: ERROR - Fee asset paused");
+                return Err(SettlementOutcome::rejected(SettlementResult::AssetPaused));
+            }
+            if instruction.fee_amount < 0 {
+                panic!("Fee amount must not be negative: {}", instruction.fee_amount);
+            }
+        }
+
+        // One account on both sides would only wash-trade through the vault
+        if instruction.buy_user == instruction.sell_user {
+            log!(env, "settle_trade: ERROR - Buyer and seller are the same account");
+            return Err(SettlementOutcome::rejected(SettlementResult::SelfTrade));
+        }
+
+        check_positive_amount(instruction.base_amount);
+        check_positive_amount(instruction.quote_amount);
+
+        trace!(env, "settle_trade: Verifying authorization");
+        match submission {
+            Submission::Engine => require_settlement_auth(env, instruction),
+            Submission::Batch | Submission::Queued | Submission::Preview => {}
+            Submission::PeerToPeer => {
+                instruction.buy_user.require_auth();
+                instruction.sell_user.require_auth();
+            }
+        }
+
+        // A trade ID settles once; replays are rejected before any sequence
+        // number or balance is touched
+        if storage::is_settled(env, &instruction.trade_id) {
+            log!(env, "settle_trade: ERROR - Trade already settled");
+            return Err(SettlementOutcome::rejected(SettlementResult::DuplicateTrade));
+        }
+
+        // Skip signature and proof verification for now
+        trace!(env, "settle_trade: Skipping verification (simplified flow)");
+
+        let (price, quantity) = match execution {
+            Some((price, quantity)) => {
+                if !is_consistent_execution(env, instruction, price, quantity) {
+                    log!(env, "settle_trade: ERROR - Execution price or quantity inconsistent with amounts");
+                    return Err(SettlementOutcome::rejected(SettlementResult::InconsistentExecution));
+                }
+                (price, quantity)
+            }
+            None => {
+                // A quote amount too large to price is rejected rather than
+                // trapping, so it cannot wedge the settlement queue
+                let scaled = instruction.quote_amount.checked_mul(storage::get_price_scale(env));
+                let Some(scaled) = scaled else {
+                    log!(env, "settle_trade: ERROR - Execution price overflows");
+                    return Err(SettlementOutcome::rejected(SettlementResult::PriceOutOfBounds));
+                };
+                (scaled / instruction.base_amount, instruction.base_amount)
+            }
+        };
+
+        // Check execution price against the instruction's price bounds
+        trace!(env, "settle_trade: Checking price bounds");
+        if (instruction.min_price != 0 && price < instruction.min_price)
+            || (instruction.max_price != 0 && price > instruction.max_price)
+        {
+            log!(env, "settle_trade: ERROR - Execution price out of bounds");
+            return Err(SettlementOutcome::rejected(SettlementResult::PriceOutOfBounds));
+        }
+
+        // The execution price must cross both orders' limits
+        if (instruction.sell_limit_price != 0 && price < instruction.sell_limit_price)
+            || (instruction.buy_limit_price != 0 && price > instruction.buy_limit_price)
+        {
+            log!(env, "settle_trade: ERROR - Execution price does not cross the order limits");
+            return Err(SettlementOutcome::rejected(SettlementResult::InvalidMatchingProof));
+        }
+
+        if fees::exceeds_fee_cap(env, instruction) {
+            log!(env, "settle_trade: ERROR - Fee exceeds cap");
+            return Err(SettlementOutcome::rejected(SettlementResult::FeeTooHigh));
+        }
+
+        if fees::has_forbidden_base_fee(env, instruction) {
+            log!(env, "settle_trade: ERROR - Base fee in quote-only fee mode");
+            return Err(SettlementOutcome::rejected(SettlementResult::BaseFeeNotAllowed));
+        }
+
+        if fees::has_negative_taker_fee(env, instruction) {
+            log!(env, "settle_trade: ERROR - Negative taker fee");
+            return Err(SettlementOutcome::rejected(SettlementResult::NegativeTakerFee));
+        }
+
+        // 4. Check vault balances
+        trace!(env, "settle_trade: Step 5 - Checking vault balances");
+        let mut balances = storage::BalanceBatch::default();
+        let buy_balance = balances.get(env, &instruction.buy_user, &instruction.quote_asset);
+        let sell_balance = balances.get(env, &instruction.sell_user, &instruction.base_asset);
+        
+        let fee_mode = storage::get_fee_mode(env);
+        let trade_fees = fees::settlement_fees(env, instruction, fee_mode);
+        if fees::exceeds_fee_ratio(env, instruction, &trade_fees) {
+            log!(env, "settle_trade: ERROR - Total fee exceeds ratio cap");
+            return Err(SettlementOutcome::rejected(SettlementResult::ExcessiveFee));
+        }
+        let rebate = fees::maker_rebate(env, instruction, &trade_fees, fee_mode);
+        let (required_quote, required_base) = trade_fees.required_amounts(instruction);
+        // In quote-only fee mode the seller's fee comes out of its proceeds
+        let seller_quote = instruction.quote_amount - trade_fees.seller_quote;
+
+        trace!(env, "settle_trade: Checking buyer quote balance and seller base balance");
+
+        if buy_balance < required_quote {
+            log!(env, "settle_trade: ERROR - Buyer has insufficient quote balance");
+            log!(env, "settle_trade: Buyer balance less than required quote amount, returning InsufficientBalance");
+            return Err(SettlementOutcome::shortfall(
+                SettlementResult::InsufficientBalance,
+                &instruction.buy_user,
+                quote,
+                required_quote,
+                buy_balance,
+            ));
+        }
+
+        if sell_balance < required_base {
+            log!(env, "settle_trade: ERROR - Seller has insufficient base balance");
+            log!(env, "settle_trade: Seller balance less than required base amount, returning InsufficientBalance");
+            return Err(SettlementOutcome::shortfall(
+                SettlementResult::InsufficientBalance,
+                &instruction.sell_user,
+                base,
+                required_base,
+                sell_balance,
+            ));
+        }
+
+        // The taker pays any separate fee-token fee
+        let fee_payer = match instruction.taker_side {
+            Side::Buy => &instruction.buy_user,
+            Side::Sell => &instruction.sell_user,
+        };
+        let token_fee = match &instruction.fee_asset {
+            Some(fee_asset) if instruction.fee_amount > 0 => Some((fee_asset, instruction.fee_amount)),
+            _ => None,
+        };
+        if let Some((fee_asset, fee_amount)) = token_fee {
+            let fee_balance = balances.get(env, fee_payer, fee_asset);
+            if fee_balance < fee_amount {
+                log!(env, "settle_trade: ERROR - Taker has insufficient fee asset balance");
+                return Err(SettlementOutcome::shortfall(
+                    SettlementResult::InsufficientBalance,
+                    fee_payer,
+                    fee_asset,
+                    fee_amount,
+                    fee_balance,
+                ));
+            }
+        }
+
+        trace!(env, "settle_trade: All balance checks passed");
+
+        // A negative maker fee is paid out of the protocol fee balance, which
+        // must already hold enough to cover it
+        let (protocol_rebate_base, protocol_rebate_quote) = trade_fees.protocol_rebates();
+        for (asset, protocol_rebate) in [(base, protocol_rebate_base), (quote, protocol_rebate_quote)] {
+            let fee_balance = storage::get_fee_balance(env, asset);
+            if protocol_rebate > 0 && fee_balance < protocol_rebate {
+                log!(env, "settle_trade: ERROR - Protocol fee balance cannot cover maker rebate");
+                return Err(SettlementOutcome::shortfall(
+                    SettlementResult::InsufficientProtocolBalance,
+                    &env.current_contract_address(),
+                    asset,
+                    protocol_rebate,
+                    fee_balance,
+                ));
+            }
+        }
+
+        // Respect any per-user cap on what the engine may settle; trades the
+        // users authorize themselves are not bound by it
+        let engine_allowance = |user: &Address, asset: &Address| match submission {
+            Submission::PeerToPeer => None,
+            Submission::Engine | Submission::Batch | Submission::Queued | Submission::Preview => {
+                storage::get_engine_allowance(env, user, asset)
+            }
+        };
+        let buy_allowance = engine_allowance(&instruction.buy_user, &instruction.quote_asset);
+        let sell_allowance = engine_allowance(&instruction.sell_user, &instruction.base_asset);
+        let fee_allowance = token_fee.and_then(|(fee_asset, _)| engine_allowance(fee_payer, fee_asset));
+        let mut draws = [
+            (&instruction.buy_user, quote, required_quote, buy_allowance),
+            (&instruction.sell_user, base, required_base, sell_allowance),
+        ]
+        .into_iter()
+        .chain(token_fee.map(|(fee_asset, fee_amount)| (fee_payer, fee_asset, fee_amount, fee_allowance)));
+        if let Some((user, asset, required, Some(allowance))) =
+            draws.find(|(_, _, required, allowance)| allowance.is_some_and(|allowance| allowance < *required))
+        {
+            log!(env, "settle_trade: ERROR - Engine allowance exceeded");
+            return Err(SettlementOutcome::shortfall(
+                SettlementResult::AllowanceExceeded,
+                user,
+                asset,
+                required,
+                allowance,
+            ));
+        }
+
+        // 5. Move assets between the vault balances, in memory only
+        trace!(env, "settle_trade: Step 5 - Executing asset transfers");
+        let mode = storage::get_settlement_mode(env);
+        // Buyer pays quote asset, receives base asset
+        trace!(env, "settle_trade: Transferring quote from buyer");
+        balances.subtract(env, &instruction.buy_user, &instruction.quote_asset, required_quote);
+        if mode == SettlementMode::Internal {
+            trace!(env, "settle_trade: Transferring base to buyer");
+            balances.add(env, &instruction.buy_user, &instruction.base_asset, instruction.base_amount);
+        }
+
+        // Seller pays base asset, receives quote asset
+        trace!(env, "settle_trade: Transferring base from seller");
+        balances.subtract(env, &instruction.sell_user, &instruction.base_asset, required_base);
+        if mode == SettlementMode::Internal {
+            trace!(env, "settle_trade: Transferring quote to seller");
+            balances.add(env, &instruction.sell_user, &instruction.quote_asset, seller_quote);
+        }
+
+        // The maker's rebate is credited to its vault balance in either mode,
+        // in the asset the taker paid its fee in
+        let (buyer_rebate, seller_rebate) = match instruction.taker_side {
+            Side::Buy => (0, rebate),
+            Side::Sell => (rebate, 0),
+        };
+        let buyer_rebate_asset = match fee_mode {
+            FeeMode::PerLeg => base,
+            FeeMode::QuoteOnly => quote,
+        };
+        if buyer_rebate > 0 {
+            balances.add(env, &instruction.buy_user, buyer_rebate_asset, buyer_rebate);
+        }
+        if seller_rebate > 0 {
+            balances.add(env, &instruction.sell_user, &instruction.quote_asset, seller_rebate);
+        }
+        if protocol_rebate_base > 0 {
+            balances.add(env, &instruction.sell_user, base, protocol_rebate_base);
+        }
+        if protocol_rebate_quote > 0 {
+            balances.add(env, &instruction.buy_user, quote, protocol_rebate_quote);
+        }
+        if let Some((fee_asset, fee_amount)) = token_fee {
+            trace!(env, "settle_trade: Charging taker fee in fee asset");
+            balances.subtract(env, fee_payer, fee_asset, fee_amount);
+        }
+        trace!(env, "settle_trade: Asset transfers completed");
+
+        Ok(SettlementPlan {
+            price,
+            quantity,
+            mode,
+            fee_mode,
+            trade_fees,
+            rebate,
+            required_quote,
+            required_base,
+            seller_quote,
+            fee_payer,
+            token_fee,
+            protocol_rebates: (protocol_rebate_base, protocol_rebate_quote),
+            allowances: (buy_allowance, sell_allowance, fee_allowance),
+            maker_rebates: (buyer_rebate, seller_rebate),
+            buyer_rebate_asset,
+            balances,
+        })
+    }
+
+    /// Query trade history for a user
+    pub fn get_trade_history(env: Env, user: Address, limit: u32) -> Vec<SettlementRecord> {
+        storage::get_trade_history(&env, &user, limit)
+    }
+
+    /// Get the number of trades a user has settled, as buyer or seller
+    pub fn get_trade_count(env: Env, user: Address) -> u32 {
+        storage::get_user_trade_stats(&env, &user).map_or(0, |(count, _, _)| count)
+    }
+
+    /// Get the earliest and latest timestamps of a user's settled trades,
+    /// or `None` if the user has not traded
+    pub fn get_user_trade_range(env: Env, user: Address) -> Option<(u64, u64)> {
+        storage::get_user_trade_stats(&env, &user).map(|(_, first, last)| (first, last))
+    }
+
+    /// Query a user's most recent `limit` deposits, oldest first
+    pub fn get_deposit_history(env: Env, user: Address, limit: u32) -> Vec<VaultMovement> {
+        storage::get_deposit_history(&env, &user, limit)
+    }
+
+    /// Query a user's most recent `limit` withdrawals, oldest first
+    pub fn get_withdrawal_history(env: Env, user: Address, limit: u32) -> Vec<VaultMovement> {
+        storage::get_withdrawal_history(&env, &user, limit)
+    }
+
+    /// Query a user's trades newest first, only those where they bought
+    /// (`Some(Side::Buy)`) or sold (`Some(Side::Sell)`), or both with `None`
+    /// `offset` skips that many of the most recent matching trades
+    pub fn get_trade_history_filtered(
+        env: Env,
+        user: Address,
+        side: Option<Side>,
+        offset: u32,
+        limit: u32,
+    ) -> Vec<SettlementRecord> {
+        storage::get_trade_history_filtered(&env, &user, side, offset, limit)
+    }
+
+    /// Query the IDs of a user's most recent `limit` trades, oldest first
+    /// A lightweight companion to get_trade_history for clients that fetch
+    /// selected records afterwards with get_settlements
+    pub fn get_trade_ids(env: Env, user: Address, limit: u32) -> Vec<BytesN<32>> {
+        storage::get_trade_ids(&env, &user, limit)
+    }
+
+    /// Query a user's trades against a specific counterparty
+    /// Returns the most recent `limit` matches in chronological order
+    pub fn get_trades_with(env: Env, user: Address, counterparty: Address, limit: u32) -> Vec<SettlementRecord> {
+        storage::get_trades_with(&env, &user, &counterparty, limit)
+    }
+
+    /// Query compact trade summaries for a user, newest first
+    /// `offset` skips that many of the most recent trades
+    pub fn get_trade_summaries(env: Env, user: Address, offset: u32, limit: u32) -> Vec<TradeSummary> {
+        storage::get_trade_summaries(&env, &user, offset, limit)
+    }
+
+    /// Page through a user's trades newest first. Pass `None` for the first
+    /// page and each page's `next_cursor` for the next; the cursor is a
+    /// position in the user's history, so trades settled between calls
+    /// neither shift nor repeat records. `next_cursor` is None after the oldest
+    pub fn get_trade_history_cursor(env: Env, user: Address, cursor: Option<u64>, limit: u32) -> TradeHistoryPage {
+        storage::get_trade_history_page(&env, &user, cursor, limit)
+    }
+
+    /// Query the most recent settlements across all users, newest first, as
+    /// compact summaries for a public trades ticker. `side` is the taker's
+    /// side, and `limit` is capped at MAX_RECENT_SETTLEMENTS
+    pub fn get_recent_settlements(env: Env, limit: u32) -> Vec<TradeSummary> {
+        storage::get_recent_settlements(&env, limit.min(MAX_RECENT_SETTLEMENTS))
+    }
+
+    /// Page through everything that changed a user's vault balance, oldest
+    /// first: deposits, withdrawals, imports, each asset a settlement moved
+    /// (as Buy or Sell), referral rebates and keeper rewards. Pass `None`
+    /// for the first page and each page's `next_cursor` for the next;
+    /// `limit` is capped at MAX_ACTIVITY_RESULTS
+    pub fn get_activity(env: Env, user: Address, cursor: Option<u64>, limit: u32) -> ActivityPage {
+        storage::get_activity(&env, &user, cursor, limit.min(MAX_ACTIVITY_RESULTS))
+    }
+
+    /// Page through the global settlement feed newest first, like
+    /// get_trade_history_cursor but keyed by global sequence number.
+    /// `limit` is capped at MAX_RECENT_SETTLEMENTS
+    pub fn get_recent_settlements_cursor(env: Env, cursor: Option<u64>, limit: u32) -> TradeSummaryPage {
+        storage::get_recent_settlements_page(&env, cursor, limit.min(MAX_RECENT_SETTLEMENTS))
+    }
+
+    /// Get a settlement record by trade ID. `None` means no trade settled
+    /// under that id: records live in instance storage, which stays live
+    /// with the contract, so a stored record is never archived on its own
+    pub fn get_settlement(env: Env, trade_id: BytesN<32>) -> Option<SettlementRecord> {
+        storage::get_settlement(&env, &trade_id)
+    }
+
+    /// Whether a trade ID has been consumed by a successful settlement,
+    /// without reading its record. Settlement records are never pruned, so
+    /// once true this stays true; any future pruning must leave a marker
+    /// behind so settled IDs cannot be replayed
+    pub fn has_settled(env: Env, trade_id: BytesN<32>) -> bool {
+        storage::is_settled(&env, &trade_id)
+    }
+
+    /// Get settlement records for several trade IDs
+    /// Results are in input order, with None for unknown IDs. At most
+    /// MAX_SETTLEMENT_LOOKUP IDs may be passed
+    pub fn get_settlements(env: Env, trade_ids: Vec<BytesN<32>>) -> Vec<Option<SettlementRecord>> {
+        if trade_ids.len() > MAX_SETTLEMENT_LOOKUP {
+            log!(&env, "get_settlements: too many trade ids", trade_ids.len());
+            panic_with_error!(&env, SettlementError::TooManyTradeIds);
+        }
+        storage::get_settlements(&env, &trade_ids)
+    }
+
+    /// Get a settlement record by its global sequence number
+    pub fn get_settlement_by_sequence(env: Env, sequence: u64) -> Option<SettlementRecord> {
+        storage::get_settlement_by_sequence(&env, sequence)
+    }
+
+    /// Get up to `limit` settlement records (capped at MAX_RANGE_RESULTS)
+    /// with sequence numbers in [from_seq, to_seq), oldest first. The span
+    /// may be at most MAX_SEQUENCE_SPAN; sequence numbers with no record
+    /// are skipped. An indexer catching up pages by passing the last
+    /// returned sequence + 1 as the next `from_seq`, up to
+    /// get_latest_sequence() + 1
+    pub fn get_settlement_range(env: Env, from_seq: u64, to_seq: u64, limit: u32) -> Vec<SettlementRecord> {
+        if from_seq > to_seq {
+            panic!("Invalid range: {} > {}", from_seq, to_seq);
+        }
+        if to_seq - from_seq > MAX_SEQUENCE_SPAN {
+            panic!("Range exceeds {} sequences", MAX_SEQUENCE_SPAN);
+        }
+        storage::get_settlement_range(&env, from_seq, to_seq, limit.min(MAX_RANGE_RESULTS))
+    }
+
+    /// Get the sequence number of the most recent settlement (0 if none)
+    pub fn get_latest_sequence(env: Env) -> u64 {
+        storage::get_latest_sequence(&env)
+    }
+
+    /// Monitoring snapshot: whether any supported asset is paused, the ledger
+    /// timestamp the contract sees, and per-asset liabilities against the
+    /// tokens the vault holds. A liability above the vault balance means
+    /// the vault is short
+    pub fn health(env: Env) -> HealthStatus {
+        use soroban_sdk::token::TokenClient;
+        let mut paused = false;
+        let mut assets = Vec::new(&env);
+        for asset in storage::get_supported_assets(&env) {
+            paused |= storage::is_asset_paused(&env, &asset);
+            let vault_balance = TokenClient::new(&env, &asset).balance(&env.current_contract_address());
+            assets.push_back(AssetHealth {
+                liability: storage::get_liability(&env, &asset),
+                vault_balance,
+                asset,
+            });
+        }
+        HealthStatus {
+            paused,
+            timestamp: env.ledger().timestamp(),
+            assets,
+        }
+    }
+
+    /// Compare the tokens the contract holds in an asset with what the vault
+    /// owes (see health) and emit the result. Anyone can call this. A deficit
+    /// pauses the asset if the admin enabled set_auto_pause_on_deficit
+    pub fn reconcile(env: Env, asset: Address) -> ReconciliationReport {
+        use soroban_sdk::token::TokenClient;
+        check_supported_asset(&env, &asset);
+        let token_balance = TokenClient::new(&env, &asset).balance(&env.current_contract_address());
+        let internal_total = storage::get_liability(&env, &asset);
+        let surplus = token_balance - internal_total;
+        if surplus < 0 && storage::is_auto_pause_on_deficit(&env) {
+            storage::set_asset_paused(&env, &asset, true);
+        }
+        let report = ReconciliationReport {
+            paused: storage::is_asset_paused(&env, &asset),
+            asset,
+            token_balance,
+            internal_total,
+            surplus,
+        };
+        events::emit_reconciled_event(&env, &report);
+        report
+    }
+
+    /// Let reconcile pause an asset whose token balance falls short of what
+    /// the vault owes. Unpausing stays a manual admin decision.
+    /// Only admin can call this
+    pub fn set_auto_pause_on_deficit(env: Env, enabled: bool) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        let previous = storage::is_auto_pause_on_deficit(&env);
+        storage::set_auto_pause_on_deficit(&env, enabled);
+        events::emit_setting_changed_event(&env, "auto_pause", previous.into(), enabled.into(), &admin);
+    }
+
+    /// Whether reconcile pauses an asset on a deficit
+    pub fn is_auto_pause_on_deficit(env: Env) -> bool {
+        storage::is_auto_pause_on_deficit(&env)
+    }
+
+    /// Set the base volume (in the pair's canonical base asset) a get_vwap
+    /// window must reach before it reports a price, so a few thin trades
+    /// cannot set the reference price. Only admin can call this
+    pub fn set_vwap_min_volume(env: Env, min_volume: i128) {
+        let admin = storage::get_admin(&env);
+        admin.require_auth();
+        if min_volume < 0 {
+            panic!("Minimum volume must not be negative: {}", min_volume);
+        }
+        let previous = storage::get_vwap_min_volume(&env);
+        storage::set_vwap_min_volume(&env, min_volume);
+        events::emit_setting_changed_event(&env, "vwap_min_volume", previous, min_volume, &admin);
+    }
+
+    /// Get the minimum VWAP window volume (0 unless set)
+    pub fn get_vwap_min_volume(env: Env) -> i128 {
+        storage::get_vwap_min_volume(&env)
+    }
+
+    /// Volume-weighted average price of a pair over the hourly buckets
+    /// (ledger timestamp / 3600) of the last `lookback_hours` hours, the
+    /// current one included: total quote settled over total base settled,
+    /// scaled by the price scale like execution prices and rounded down.
+    /// The price is quote per base in the requested orientation, so swapping
+    /// the assets gives the inverse. Returns `None` when the window's base
+    /// volume is zero or below get_vwap_min_volume. `lookback_hours` must be
+    /// between 1 and MAX_VWAP_LOOKBACK_HOURS
+    pub fn get_vwap(env: Env, base: Address, quote: Address, lookback_hours: u32) -> Option<i128> {
+        if lookback_hours == 0 || lookback_hours > MAX_VWAP_LOOKBACK_HOURS {
+            panic!("Lookback must be 1 to {} hours", MAX_VWAP_LOOKBACK_HOURS);
+        }
+        let pair = storage::pair_key(&env, &base, &quote);
+        let current_hour = env.ledger().timestamp() / storage_types::HOUR_SECONDS;
+        let first_hour = current_hour.saturating_sub(lookback_hours as u64 - 1);
+        let (mut base_total, mut quote_total) = (0i128, 0i128);
+        for hour in first_hour..=current_hour {
+            let (base_amount, quote_amount) = storage::get_hourly_volume(&env, &pair, hour);
+            base_total += base_amount;
+            quote_total += quote_amount;
+        }
+        if base_total == 0 || base_total < storage::get_vwap_min_volume(&env) {
+            return None;
+        }
+        if pair.base == base {
+            Some(execution_price(&env, base_total, quote_total))
+        } else {
+            Some(execution_price(&env, quote_total, base_total))
+        }
+    }
+
+    /// Get a pair's settled quote volume and trade count on `day`
+    /// (timestamp / 86_400). Either asset ordering names the same pair, and
+    /// volume is in its canonical quote asset. A day without trades reads as zero
+    pub fn get_daily_volume(env: Env, base: Address, quote: Address, day: u64) -> DailyVolume {
+        storage::get_daily_volume(&env, &storage::pair_key(&env, &base, &quote), day)
+    }
+
+    /// Get a pair's daily volume for each day in `from_day..=to_day`, oldest
+    /// first and including days without trades, for charting. At most `limit`
+    /// days (capped at MAX_RANGE_DAYS) are returned
+    pub fn get_volume_range(env: Env, base: Address, quote: Address, from_day: u64, to_day: u64, limit: u32) -> Vec<DailyVolume> {
+        if from_day > to_day {
+            panic!("Invalid range: {} > {}", from_day, to_day);
+        }
+        let pair = storage::pair_key(&env, &base, &quote);
+        let mut volumes = Vec::new(&env);
+        for day in (from_day..=to_day).take((limit as u64).min(MAX_RANGE_DAYS) as usize) {
+            volumes.push_back(storage::get_daily_volume(&env, &pair, day));
+        }
+        volumes
+    }
+
+    /// Get a pair's lifetime totals: trade count, base and quote volume and
+    /// the latest trade's timestamp. Either asset ordering names the same
+    /// pair, and volumes are in its canonical orientation
+    pub fn get_pair_stats(env: Env, base: Address, quote: Address) -> PairStats {
+        storage::get_pair_stats(&env, &storage::pair_key(&env, &base, &quote))
+    }
+
+    /// Get a pair's largest settlement by quote notional, if it has traded.
+    /// Either asset ordering names the same pair. Ties keep the earlier
+    /// trade, and the entry keeps its own copy of the trade's amounts
+    pub fn get_largest_trade(env: Env, base: Address, quote: Address) -> Option<LargestTrade> {
+        let pair = storage::pair_key(&env, &base, &quote);
+        storage::get_largest_trade(&env, &storage_types::MarketDataKey::PairLargestTrade(pair))
+    }
+
+    /// Get the largest settlement by quote notional across every pair.
+    /// Notionals in different quote assets are compared as raw amounts
+    pub fn get_largest_trade_global(env: Env) -> Option<LargestTrade> {
+        storage::get_largest_trade(&env, &storage_types::MarketDataKey::LargestTrade)
+    }
+
+    /// Get the latest execution price between two assets, as `quote` per
+    /// `base` scaled by the price scale, or None if they have never traded.
+    /// Trades listing the pair either way round update it; the price is
+    /// inverted for the ordering asked for (0 if it cannot be inverted)
+    pub fn get_last_price(env: Env, base: Address, quote: Address) -> Option<i128> {
+        let pair = storage::pair_key(&env, &base, &quote);
+        let price = storage::get_last_price(&env, &pair)?;
+        if pair.base == base {
+            return Some(price);
+        }
+        let scale = storage::get_price_scale(&env);
+        Some(scale.checked_mul(scale).expect("Price overflow").checked_div(price).unwrap_or(0))
+    }
+
+    /// Value a user's vault balances (locked funds included) in `quote_asset`
+    /// at each pair's latest execution price against it, counting
+    /// `quote_asset` itself 1:1. Assets that have never traded against
+    /// `quote_asset` add nothing, and each conversion rounds down
+    pub fn get_portfolio_value(env: Env, user: Address, quote_asset: Address) -> i128 {
+        check_supported_asset(&env, &quote_asset);
+        let scale = storage::get_price_scale(&env);
+        let mut value: i128 = 0;
+        for asset in storage::get_supported_assets(&env).iter() {
+            let balance = storage::get_balance(&env, &user, &asset);
+            if balance == 0 {
+                continue;
+            }
+            let asset_value = if asset == quote_asset {
+                balance
+            } else {
+                let pair = storage::pair_key(&env, &asset, &quote_asset);
+                match storage::get_last_price(&env, &pair) {
+                    Some(price) if pair.base == asset => balance.checked_mul(price).expect("Value overflow") / scale,
+                    Some(price) if price > 0 => balance.checked_mul(scale).expect("Value overflow") / price,
+                    _ => 0,
+                }
+            };
+            value = value.checked_add(asset_value).expect("Value overflow");
+        }
+        value
+    }
+
+    /// Orient two supported assets as a canonical (base, quote) pair: the
+    /// asset registered earlier (see get_supported_assets) is the base,
+    /// whichever order they are given. For the traded asset_a/asset_b pair
+    /// this is the orientation of every per-pair query and record key
+    pub fn normalize_pair(env: Env, a: Address, b: Address) -> (Address, Address) {
+        check_supported_asset(&env, &a);
+        check_supported_asset(&env, &b);
+        if a == b {
+            panic!("Pair needs two distinct assets");
+        }
+        let assets = storage::get_supported_assets(&env);
+        if assets.first_index_of(&b) < assets.first_index_of(&a) {
+            (b, a)
+        } else {
+            (a, b)
+        }
+    }
+
+    /// Get venue-level lifetime totals: trade count, per-asset base and quote
+    /// volume, per-asset fees charged and the latest settlement's timestamp
+    pub fn get_stats(env: Env) -> GlobalStats {
+        storage::get_global_stats(&env)
+    }
+
+    /// Get storage usage counters for monitoring ledger entry growth
+    pub fn get_storage_stats(env: Env) -> StorageStats {
+        storage::get_storage_stats(&env)
     }
 }
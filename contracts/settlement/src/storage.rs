@@ -1,6 +1,25 @@
 use crate::storage_types::*;
 use crate::types::*;
-use soroban_sdk::{Address, BytesN, Env, Vec};
+use soroban_sdk::{vec, Address, BytesN, Env, Map, Vec};
+
+/// Keep the instance entry (configuration and vault state) alive
+/// Called on routine activity so an active contract never expires
+pub fn extend_instance_ttl(env: &Env) {
+    env.storage()
+        .instance()
+        .extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+}
+
+/// Whether the constructor has already run
+pub fn is_initialized(env: &Env) -> bool {
+    let key = DataKey::Initialized;
+    env.storage().instance().has(&key)
+}
+
+pub fn set_initialized(env: &Env) {
+    let key = DataKey::Initialized;
+    env.storage().instance().set(&key, &true);
+}
 
 pub fn set_admin(env: &Env, admin: &Address) {
     let key = DataKey::Admin;
@@ -8,8 +27,13 @@ pub fn set_admin(env: &Env, admin: &Address) {
 }
 
 pub fn get_admin(env: &Env) -> Address {
+    try_get_admin(env).unwrap()
+}
+
+/// The admin, or None on a contract deployed without constructor arguments
+pub fn try_get_admin(env: &Env) -> Option<Address> {
     let key = DataKey::Admin;
-    env.storage().instance().get(&key).unwrap()
+    env.storage().instance().get(&key)
 }
 
 pub fn get_asset_a(env: &Env) -> Address {
@@ -22,6 +46,79 @@ pub fn get_asset_b(env: &Env) -> Address {
     env.storage().instance().get(&key).unwrap()
 }
 
+pub fn set_asset_a(env: &Env, asset: &Address) {
+    let key = DataKey::AssetA;
+    env.storage().instance().set(&key, asset);
+}
+
+pub fn set_asset_b(env: &Env, asset: &Address) {
+    let key = DataKey::AssetB;
+    env.storage().instance().set(&key, asset);
+}
+
+/// Every asset the contract accepts, in configuration order: the pair's
+/// two assets, then any registered since
+pub fn get_supported_assets(env: &Env) -> Vec<Address> {
+    let mut assets = vec![env, get_asset_a(env), get_asset_b(env)];
+    assets.append(&get_extra_assets(env));
+    assets
+}
+
+/// Assets registered after deployment, e.g. a dedicated fee token
+pub fn get_extra_assets(env: &Env) -> Vec<Address> {
+    let key = DataKey::ExtraAssets;
+    env.storage().instance().get(&key).unwrap_or_else(|| Vec::new(env))
+}
+
+pub fn set_extra_assets(env: &Env, assets: &Vec<Address>) {
+    let key = DataKey::ExtraAssets;
+    env.storage().instance().set(&key, assets);
+}
+
+/// Whether balance imports are closed: sealed by the admin, or implicitly
+/// once the first settlement has been recorded
+pub fn is_import_closed(env: &Env) -> bool {
+    env.storage().instance().has(&DataKey::ImportClosed) || get_latest_sequence(env) > 0
+}
+
+pub fn close_import(env: &Env) {
+    env.storage().instance().set(&DataKey::ImportClosed, &true);
+}
+
+/// Addresses whose approvals replace the admin's on sensitive operations
+pub fn get_admin_signers(env: &Env) -> Vec<Address> {
+    let key = DataKey::AdminSigners;
+    env.storage().instance().get(&key).unwrap_or_else(|| Vec::new(env))
+}
+
+/// Approvals required from the admin signers; 0 means the admin alone decides
+pub fn get_admin_threshold(env: &Env) -> u32 {
+    let key = DataKey::AdminThreshold;
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Store the admin signer set and threshold, or clear both when `threshold` is 0
+pub fn set_admin_signers(env: &Env, signers: &Vec<Address>, threshold: u32) {
+    if threshold == 0 {
+        env.storage().instance().remove(&DataKey::AdminSigners);
+        env.storage().instance().remove(&DataKey::AdminThreshold);
+    } else {
+        env.storage().instance().set(&DataKey::AdminSigners, signers);
+        env.storage().instance().set(&DataKey::AdminThreshold, &threshold);
+    }
+}
+
+/// Fixed-point scale for prices; stored at construction
+pub fn get_price_scale(env: &Env) -> i128 {
+    let key = DataKey::PriceScale;
+    env.storage().instance().get(&key).unwrap()
+}
+
+pub fn set_price_scale(env: &Env, price_scale: i128) {
+    let key = DataKey::PriceScale;
+    env.storage().instance().set(&key, &price_scale);
+}
+
 /// Set the matching engine address (authorized to call settle_trade)
 pub fn set_matching_engine(env: &Env, matching_engine: &Address) {
     let key = DataKey::MatchingEngine;
@@ -36,109 +133,1971 @@ pub fn get_matching_engine(env: &Env) -> Option<Address> {
     env.storage().instance().get(&key)
 }
 
-/// Get user balance for a specific asset
-pub fn get_balance(env: &Env, user: &Address, asset: &Address) -> i128 {
-    let key = DataKey::Balance(BalanceDataKey {
+/// Check whether deposits and settlements are paused for an asset
+pub fn is_asset_paused(env: &Env, asset: &Address) -> bool {
+    let key = DataKey::AssetPaused(asset.clone());
+    env.storage().instance().has(&key)
+}
+
+/// Pause or unpause deposits and settlements for an asset
+pub fn set_asset_paused(env: &Env, asset: &Address, paused: bool) {
+    let key = DataKey::AssetPaused(asset.clone());
+    if paused {
+        env.storage().instance().set(&key, &true);
+    } else {
+        env.storage().instance().remove(&key);
+    }
+}
+
+/// How settlements deliver proceeds (defaults to internal vault credits)
+pub fn get_settlement_mode(env: &Env) -> SettlementMode {
+    let key = DataKey::SettlementMode;
+    env.storage()
+        .instance()
+        .get(&key)
+        .unwrap_or(SettlementMode::Internal)
+}
+
+pub fn set_settlement_mode(env: &Env, mode: SettlementMode) {
+    let key = DataKey::SettlementMode;
+    env.storage().instance().set(&key, &mode);
+}
+
+pub fn is_batch_summary_only(env: &Env) -> bool {
+    let key = DataKey::BatchSummaryOnly;
+    env.storage().instance().get(&key).unwrap_or(false)
+}
+
+pub fn set_batch_summary_only(env: &Env, summary_only: bool) {
+    let key = DataKey::BatchSummaryOnly;
+    if summary_only {
+        env.storage().instance().set(&key, &true);
+    } else {
+        env.storage().instance().remove(&key);
+    }
+}
+
+pub fn is_auto_pause_on_deficit(env: &Env) -> bool {
+    let key = DataKey::AutoPauseOnDeficit;
+    env.storage().instance().get(&key).unwrap_or(false)
+}
+
+pub fn set_auto_pause_on_deficit(env: &Env, enabled: bool) {
+    let key = DataKey::AutoPauseOnDeficit;
+    if enabled {
+        env.storage().instance().set(&key, &true);
+    } else {
+        env.storage().instance().remove(&key);
+    }
+}
+
+pub fn get_deposit_cap(env: &Env, asset: &Address) -> Option<i128> {
+    let key = DataKey::DepositCap(asset.clone());
+    env.storage().instance().get(&key)
+}
+
+/// Set or (with `None`) remove an asset's deposit cap
+pub fn set_deposit_cap(env: &Env, asset: &Address, cap: Option<i128>) {
+    let key = DataKey::DepositCap(asset.clone());
+    match cap {
+        Some(cap) => env.storage().instance().set(&key, &cap),
+        None => env.storage().instance().remove(&key),
+    }
+}
+
+pub fn get_user_deposit_cap(env: &Env, user: &Address, asset: &Address) -> Option<i128> {
+    let key = DataKey::UserDepositCap(BalanceDataKey {
         user: user.clone(),
         asset: asset.clone(),
     });
-    env.storage().instance().get(&key).unwrap_or(0)
+    env.storage().persistent().get(&key)
 }
 
-/// Set user balance for a specific asset
-pub fn set_balance(env: &Env, user: &Address, asset: &Address, amount: i128) {
-    let key = DataKey::Balance(BalanceDataKey {
+/// Set or (with `None`) remove a user's own deposit cap for an asset,
+/// keeping the stored entry count in step
+pub fn set_user_deposit_cap(env: &Env, user: &Address, asset: &Address, cap: Option<i128>) {
+    let key = DataKey::UserDepositCap(BalanceDataKey {
         user: user.clone(),
         asset: asset.clone(),
     });
-    env.storage().instance().set(&key, &amount);
+    let exists = env.storage().persistent().has(&key);
+    match cap {
+        Some(cap) => {
+            if !exists {
+                update_storage_stats(env, |stats| stats.persistent_entries += 1);
+            }
+            env.storage().persistent().set(&key, &cap);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+        }
+        None if exists => {
+            update_storage_stats(env, |stats| stats.persistent_entries -= 1);
+            env.storage().persistent().remove(&key);
+        }
+        None => {}
+    }
 }
 
-/// Add to user balance (deposit)
-pub fn add_balance(env: &Env, user: &Address, asset: &Address, amount: i128) {
-    let current = get_balance(env, user, asset);
-    set_balance(env, user, asset, current + amount);
+pub fn get_fee_mode(env: &Env) -> FeeMode {
+    let key = DataKey::FeeMode;
+    env.storage().instance().get(&key).unwrap_or(FeeMode::PerLeg)
 }
 
-/// Subtract from user balance (withdraw/settlement)
-pub fn subtract_balance(env: &Env, user: &Address, asset: &Address, amount: i128) {
-    let current = get_balance(env, user, asset);
-    if current < amount {
-        panic!("Insufficient balance");
+pub fn set_fee_mode(env: &Env, mode: FeeMode) {
+    let key = DataKey::FeeMode;
+    env.storage().instance().set(&key, &mode);
+}
+
+/// Maximum sizes for variable-length instruction fields
+pub fn get_instruction_limits(env: &Env) -> InstructionLimits {
+    let key = DataKey::InstructionLimits;
+    env.storage()
+        .instance()
+        .get(&key)
+        .unwrap_or(InstructionLimits {
+            max_memo_bytes: DEFAULT_MAX_MEMO_BYTES,
+            max_proof_len: DEFAULT_MAX_PROOF_LEN,
+        })
+}
+
+pub fn set_instruction_limits(env: &Env, limits: &InstructionLimits) {
+    let key = DataKey::InstructionLimits;
+    env.storage().instance().set(&key, limits);
+}
+
+/// Who must authorize settle_trade (defaults to the matching engine)
+pub fn get_auth_mode(env: &Env) -> AuthMode {
+    let key = ConfigDataKey::AuthMode;
+    env.storage().instance().get(&key).unwrap_or(AuthMode::EngineOnly)
+}
+
+pub fn set_auth_mode(env: &Env, mode: AuthMode) {
+    let key = ConfigDataKey::AuthMode;
+    env.storage().instance().set(&key, &mode);
+}
+
+/// Rounding of fees computed from on-chain rates
+pub fn get_rounding_mode(env: &Env) -> RoundingMode {
+    let key = ConfigDataKey::RoundingMode;
+    env.storage().instance().get(&key).unwrap_or(RoundingMode::Floor)
+}
+
+pub fn set_rounding_mode(env: &Env, mode: RoundingMode) {
+    let key = ConfigDataKey::RoundingMode;
+    env.storage().instance().set(&key, &mode);
+}
+
+/// Share of a trade's quote fees rebated to the referrer it names (0 until set)
+pub fn get_referral_bps(env: &Env) -> u32 {
+    let key = ConfigDataKey::ReferralBps;
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+pub fn set_referral_bps(env: &Env, referral_bps: u32) {
+    let key = ConfigDataKey::ReferralBps;
+    env.storage().instance().set(&key, &referral_bps);
+}
+
+/// Seconds per volume-stats epoch (VOLUME_EPOCH_SECONDS until set)
+pub fn get_volume_epoch_length(env: &Env) -> u64 {
+    let key = ConfigDataKey::VolumeEpochLength;
+    env.storage().instance().get(&key).unwrap_or(VOLUME_EPOCH_SECONDS)
+}
+
+pub fn set_volume_epoch_length(env: &Env, seconds: u64) {
+    let key = ConfigDataKey::VolumeEpochLength;
+    env.storage().instance().set(&key, &seconds);
+}
+
+/// On-chain maker/taker fee rates; `None` means instruction-supplied fees
+pub fn get_fee_rates(env: &Env) -> Option<FeeRates> {
+    let key = DataKey::FeeRates;
+    env.storage().instance().get(&key)
+}
+
+pub fn set_fee_rates(env: &Env, rates: &FeeRates) {
+    let key = DataKey::FeeRates;
+    env.storage().instance().set(&key, rates);
+}
+
+/// Canonical key for a trading pair, oriented as the contract's
+/// (asset_a, asset_b) so that either instruction ordering maps to one key
+pub fn pair_key(env: &Env, base: &Address, quote: &Address) -> AssetPairKey {
+    if base == &get_asset_b(env) && quote == &get_asset_a(env) {
+        AssetPairKey {
+            base: quote.clone(),
+            quote: base.clone(),
+        }
+    } else {
+        AssetPairKey {
+            base: base.clone(),
+            quote: quote.clone(),
+        }
     }
-    set_balance(env, user, asset, current - amount);
 }
 
-pub fn record_settlement(env: &Env, instruction: &SettlementInstruction) {
-    let record = SettlementRecord {
-        trade_id: instruction.trade_id.clone(),
-        buy_user: instruction.buy_user.clone(),
-        sell_user: instruction.sell_user.clone(),
-        base_asset: instruction.base_asset.clone(),
-        quote_asset: instruction.quote_asset.clone(),
-        base_amount: instruction.base_amount,
-        quote_amount: instruction.quote_amount,
-        execution_price: 0, // Placeholder - no matching proof
-        execution_quantity: 0, // Placeholder - no matching proof
-        timestamp: instruction.timestamp,
-    };
+pub fn get_pair_fee_rates(env: &Env, pair: &AssetPairKey) -> Option<FeeRates> {
+    let key = DataKey::PairFeeRates(pair.clone());
+    env.storage().instance().get(&key)
+}
 
-    // Store by trade ID
-    let trade_key = DataKey::Settlement(instruction.trade_id.clone());
-    env.storage().instance().set(&trade_key, &record);
+pub fn set_pair_fee_rates(env: &Env, pair: &AssetPairKey, rates: &FeeRates) {
+    let key = DataKey::PairFeeRates(pair.clone());
+    env.storage().instance().set(&key, rates);
+}
 
-    // Store in user trade history
-    let buy_trades_key = DataKey::UserTradeHistory(instruction.buy_user.clone());
-    let sell_trades_key = DataKey::UserTradeHistory(instruction.sell_user.clone());
+pub fn remove_pair_fee_rates(env: &Env, pair: &AssetPairKey) {
+    let key = DataKey::PairFeeRates(pair.clone());
+    env.storage().instance().remove(&key);
+}
 
-    let mut buy_trades: Vec<BytesN<32>> = env
-        .storage()
-        .instance()
-        .get(&buy_trades_key)
-        .unwrap_or_else(|| Vec::new(env));
-    let mut sell_trades: Vec<BytesN<32>> = env
-        .storage()
-        .instance()
-        .get(&sell_trades_key)
-        .unwrap_or_else(|| Vec::new(env));
+/// Volume fee tiers as (minimum epoch volume, bps), ascending by volume
+pub fn get_fee_tiers(env: &Env) -> Vec<(i128, u32)> {
+    let key = DataKey::FeeTiers;
+    env.storage().instance().get(&key).unwrap_or_else(|| Vec::new(env))
+}
+
+pub fn set_fee_tiers(env: &Env, tiers: &Vec<(i128, u32)>) {
+    let key = DataKey::FeeTiers;
+    if tiers.is_empty() {
+        env.storage().instance().remove(&key);
+    } else {
+        env.storage().instance().set(&key, tiers);
+    }
+}
+
+/// Index of the fee-tier epoch containing the current ledger time
+pub fn volume_epoch(env: &Env) -> u64 {
+    env.ledger().timestamp() / VOLUME_EPOCH_SECONDS
+}
+
+/// A user's settled volume in the current epoch; earlier epochs read as 0
+pub fn get_user_volume(env: &Env, user: &Address) -> i128 {
+    let key = DataKey::UserVolume(user.clone());
+    match env.storage().persistent().get::<_, UserVolume>(&key) {
+        Some(entry) if entry.epoch == volume_epoch(env) => entry.volume,
+        _ => 0,
+    }
+}
+
+/// Add settled volume for a user, starting over when a new epoch begins
+pub fn add_user_volume(env: &Env, user: &Address, amount: i128) {
+    let key = DataKey::UserVolume(user.clone());
+    let existing: Option<UserVolume> = env.storage().persistent().get(&key);
+    if existing.is_none() {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    let epoch = volume_epoch(env);
+    let volume = match existing {
+        Some(entry) if entry.epoch == epoch => entry.volume.saturating_add(amount),
+        _ => amount,
+    };
+    env.storage().persistent().set(&key, &UserVolume { epoch, volume });
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
 
-    buy_trades.push_back(instruction.trade_id.clone());
-    sell_trades.push_back(instruction.trade_id.clone());
+/// A user's lifetime and current-epoch volume. The epoch volume is kept
+/// with its epoch's start time, so it reads as 0 once that epoch has ended
+/// (or a new epoch length moved the boundary)
+pub fn get_user_volume_stats(env: &Env, user: &Address) -> UserVolumeStats {
+    let key = UserDataKey::VolumeTotals(user.clone());
+    let (lifetime, epoch_start, epoch_volume): (i128, u64, i128) =
+        env.storage().persistent().get(&key).unwrap_or((0, 0, 0));
+    let (epoch_index, current_start) = volume_stats_epoch(env);
+    UserVolumeStats {
+        lifetime_quote_volume: lifetime,
+        current_epoch_volume: if epoch_start == current_start { epoch_volume } else { 0 },
+        epoch_index,
+    }
+}
 
-    env.storage().instance().set(&buy_trades_key, &buy_trades);
+/// Add settled volume to a user's lifetime and current-epoch totals
+pub fn add_user_volume_stats(env: &Env, user: &Address, amount: i128) {
+    let key = UserDataKey::VolumeTotals(user.clone());
+    let existing: Option<(i128, u64, i128)> = env.storage().persistent().get(&key);
+    if existing.is_none() {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    let (_, current_start) = volume_stats_epoch(env);
+    let totals = match existing {
+        Some((lifetime, epoch_start, epoch_volume)) if epoch_start == current_start => {
+            (lifetime.saturating_add(amount), current_start, epoch_volume.saturating_add(amount))
+        }
+        Some((lifetime, _, _)) => (lifetime.saturating_add(amount), current_start, amount),
+        None => (amount, current_start, amount),
+    };
+    env.storage().persistent().set(&key, &totals);
     env.storage()
-        .instance()
-        .set(&sell_trades_key, &sell_trades);
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
 }
 
-pub fn get_settlement(env: &Env, trade_id: &BytesN<32>) -> Option<SettlementRecord> {
-    let key = DataKey::Settlement(trade_id.clone());
+/// (index, start timestamp) of the volume-stats epoch containing the
+/// current ledger time
+fn volume_stats_epoch(env: &Env) -> (u64, u64) {
+    let length = get_volume_epoch_length(env);
+    let index = env.ledger().timestamp() / length;
+    (index, index * length)
+}
+
+/// Maker rebate in basis points of the maker's notional; 0 when unset
+pub fn get_maker_rebate_bps(env: &Env) -> u32 {
+    let key = DataKey::MakerRebateBps;
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+pub fn set_maker_rebate_bps(env: &Env, bps: u32) {
+    let key = DataKey::MakerRebateBps;
+    env.storage().instance().set(&key, &bps);
+}
+
+/// Cap on instruction-supplied fees in basis points; `None` means unlimited
+pub fn get_max_fee_bps(env: &Env) -> Option<u32> {
+    let key = DataKey::MaxFeeBps;
     env.storage().instance().get(&key)
 }
 
-pub fn get_trade_history(env: &Env, user: &Address, limit: u32) -> Vec<SettlementRecord> {
-    let trades_key = DataKey::UserTradeHistory(user.clone());
-    let trade_ids: Vec<BytesN<32>> = env
-        .storage()
-        .instance()
-        .get(&trades_key)
-        .unwrap_or_else(|| Vec::new(env));
+pub fn set_max_fee_bps(env: &Env, bps: u32) {
+    let key = DataKey::MaxFeeBps;
+    env.storage().instance().set(&key, &bps);
+}
 
-    let mut records = Vec::new(env);
-    let trade_ids_len_u32 = trade_ids.len();
-    let limit_u32 = limit;
-    let start_u32 = trade_ids_len_u32.saturating_sub(limit_u32);
+pub fn get_max_fee_ratio_bps(env: &Env) -> Option<u32> {
+    let key = DataKey::MaxFeeRatioBps;
+    env.storage().instance().get(&key)
+}
+
+/// Set or (with `None`) remove the total fee to notional cap
+pub fn set_max_fee_ratio_bps(env: &Env, bps: Option<u32>) {
+    let key = DataKey::MaxFeeRatioBps;
+    match bps {
+        Some(bps) => env.storage().instance().set(&key, &bps),
+        None => env.storage().instance().remove(&key),
+    }
+}
 
-    let start = start_u32;
-    let len = trade_ids_len_u32;
+/// Address allowed to change fee parameters (falls back to admin when unset)
+pub fn get_fee_manager(env: &Env) -> Option<Address> {
+    let key = DataKey::FeeManager;
+    env.storage().instance().get(&key)
+}
 
-    for i in start..len {
-        if let Some(trade_id) = trade_ids.get(i) {
-            if let Some(record) = get_settlement(env, &trade_id) {
-                records.push_back(record);
+pub fn set_fee_manager(env: &Env, fee_manager: &Address) {
+    let key = DataKey::FeeManager;
+    env.storage().instance().set(&key, fee_manager);
+}
+
+pub fn get_fee_recipient(env: &Env) -> Option<Address> {
+    let key = DataKey::FeeRecipient;
+    env.storage().instance().get(&key)
+}
+
+/// Set the fee recipient, moving the balances it holds out of open interest
+/// and the previous recipient's back in
+pub fn set_fee_recipient(env: &Env, fee_recipient: &Address) {
+    let admin = get_admin(env);
+    let previous = get_fee_recipient(env).filter(|previous| previous != &admin && previous != fee_recipient);
+    let joining = (fee_recipient != &admin && get_fee_recipient(env).as_ref() != Some(fee_recipient))
+        .then(|| fee_recipient.clone());
+    for asset in get_supported_assets(env).iter() {
+        if let Some(previous) = &previous {
+            adjust_open_interest(env, &asset, get_balance(env, previous, &asset));
+        }
+        if let Some(joining) = &joining {
+            adjust_open_interest(env, &asset, -get_balance(env, joining, &asset));
+        }
+    }
+    let key = DataKey::FeeRecipient;
+    env.storage().instance().set(&key, fee_recipient);
+}
+
+/// Get user balance for a specific asset
+pub fn get_balance(env: &Env, user: &Address, asset: &Address) -> i128 {
+    let key = DataKey::Balance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Set user balance for a specific asset
+/// Used by balance imports, and by tests to seed vault balances directly
+pub fn set_balance(env: &Env, user: &Address, asset: &Address, amount: i128) {
+    let current = get_balance(env, user, asset);
+    let delta = write_balance(env, user, asset, current, amount);
+    apply_stats_delta(env, &delta);
+    if !is_protocol_account(env, user) {
+        adjust_open_interest(env, asset, amount - current);
+    }
+}
+
+/// (head, tail) of the settlement queue: instructions at positions
+/// head..tail are waiting for process_queue
+fn get_queue_bounds(env: &Env) -> (u64, u64) {
+    env.storage().instance().get(&QueueDataKey::SettlementQueue).unwrap_or((0, 0))
+}
+
+fn set_queue_bounds(env: &Env, bounds: (u64, u64)) {
+    let key = QueueDataKey::SettlementQueue;
+    if !env.storage().instance().has(&key) {
+        update_storage_stats(env, |stats| stats.instance_entries += 1);
+    }
+    env.storage().instance().set(&key, &bounds);
+}
+
+/// Number of instructions waiting in the settlement queue
+pub fn get_queue_len(env: &Env) -> u64 {
+    let (head, tail) = get_queue_bounds(env);
+    tail - head
+}
+
+/// Append an instruction to the back of the settlement queue, each in its
+/// own persistent entry so enqueueing costs the same however long the queue
+pub fn push_queued_settlement(env: &Env, queued: &QueuedInstruction) {
+    let (head, tail) = get_queue_bounds(env);
+    let key = QueueDataKey::QueuedSettlement(tail);
+    env.storage().persistent().set(&key, queued);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    set_queue_bounds(env, (head, tail + 1));
+}
+
+/// Remove and return the instruction at the front of the settlement queue
+pub fn pop_queued_settlement(env: &Env) -> Option<QueuedInstruction> {
+    let (head, tail) = get_queue_bounds(env);
+    if head == tail {
+        return None;
+    }
+    let key = QueueDataKey::QueuedSettlement(head);
+    let queued = env.storage().persistent().get(&key);
+    env.storage().persistent().remove(&key);
+    update_storage_stats(env, |stats| stats.persistent_entries -= 1);
+    set_queue_bounds(env, (head + 1, tail));
+    queued
+}
+
+/// Part of a user's vault balance locked behind resting orders (0 if none)
+pub fn get_locked_balance(env: &Env, user: &Address, asset: &Address) -> i128 {
+    let key = VaultDataKey::LockedBalance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    env.storage().persistent().get(&key).unwrap_or(0)
+}
+
+/// Change a user's locked balance by `change`, returning the new amount
+/// The entry is dropped once nothing is locked
+pub fn adjust_locked_balance(env: &Env, user: &Address, asset: &Address, change: i128) -> i128 {
+    let key = VaultDataKey::LockedBalance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    let current: i128 = env.storage().persistent().get(&key).unwrap_or(0);
+    let locked = current.checked_add(change).expect("Locked balance overflow");
+    if locked < 0 {
+        panic!("Locked balance cannot be negative: {}", locked);
+    }
+    match (current, locked) {
+        (0, 0) => return locked,
+        (_, 0) => {
+            env.storage().persistent().remove(&key);
+            update_storage_stats(env, |stats| stats.persistent_entries -= 1);
+        }
+        (current, locked) => {
+            if current == 0 {
+                update_storage_stats(env, |stats| stats.persistent_entries += 1);
             }
+            env.storage().persistent().set(&key, &locked);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
         }
     }
+    if change < 0 {
+        draw_down_locks(env, user, asset, locked);
+    }
+    locked
+}
 
-    records
+/// Shrink a user's timed locks in an asset, soonest expiry first, until
+/// what is left of them fits in the `locked` total. A settlement or unlock
+/// that draws on the locked balance thereby draws on the locks, so
+/// unlock_expired never releases funds another lock still holds
+fn draw_down_locks(env: &Env, user: &Address, asset: &Address, locked: i128) {
+    let mut locks: Vec<(BytesN<32>, BalanceLock)> = Vec::new(env);
+    let mut excess = -locked;
+    for id in get_lock_ids(env, user, asset).iter() {
+        let lock = get_balance_lock(env, &id).expect("Lock not found");
+        excess += lock.remaining;
+        locks.push_back((id, lock));
+    }
+    while excess > 0 {
+        let mut soonest: Option<(u32, u64)> = None;
+        for (index, (_, lock)) in locks.iter().enumerate() {
+            if lock.remaining > 0 && soonest.is_none_or(|(_, expires_at)| lock.expires_at < expires_at) {
+                soonest = Some((index as u32, lock.expires_at));
+            }
+        }
+        let (index, _) = soonest.expect("Locks exceed the locked balance");
+        let (id, mut lock) = locks.get_unchecked(index);
+        let drawn = lock.remaining.min(excess);
+        lock.remaining -= drawn;
+        excess -= drawn;
+        set_balance_lock(env, &id, Some(lock.clone()));
+        locks.set(index, (id, lock));
+    }
+}
+
+/// Ids of a user's unreclaimed lock_balance_until locks in an asset
+pub fn get_lock_ids(env: &Env, user: &Address, asset: &Address) -> Vec<BytesN<32>> {
+    let key = VaultDataKey::LockIds(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env))
+}
+
+/// Store a user's lock ids in an asset, dropping the entry once empty
+fn set_lock_ids(env: &Env, user: &Address, asset: &Address, ids: &Vec<BytesN<32>>) {
+    let key = VaultDataKey::LockIds(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    let exists = env.storage().persistent().has(&key);
+    if ids.is_empty() {
+        if exists {
+            update_storage_stats(env, |stats| stats.persistent_entries -= 1);
+            env.storage().persistent().remove(&key);
+        }
+        return;
+    }
+    if !exists {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    env.storage().persistent().set(&key, ids);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Vault balance a user may withdraw: the balance less its locked part
+pub fn get_available_balance(env: &Env, user: &Address, asset: &Address) -> i128 {
+    (get_balance(env, user, asset) - get_locked_balance(env, user, asset)).max(0)
+}
+
+pub fn get_balance_lock(env: &Env, lock_id: &BytesN<32>) -> Option<BalanceLock> {
+    env.storage().persistent().get(&VaultDataKey::Lock(lock_id.clone()))
+}
+
+/// Store or (with `None`) remove a lock's metadata, keeping the stored
+/// entry count and the owner's lock ids in step
+pub fn set_balance_lock(env: &Env, lock_id: &BytesN<32>, lock: Option<BalanceLock>) {
+    let key = VaultDataKey::Lock(lock_id.clone());
+    let existing: Option<BalanceLock> = env.storage().persistent().get(&key);
+    match lock {
+        Some(lock) => {
+            if existing.is_none() {
+                update_storage_stats(env, |stats| stats.persistent_entries += 1);
+                let mut ids = get_lock_ids(env, &lock.user, &lock.asset);
+                ids.push_back(lock_id.clone());
+                set_lock_ids(env, &lock.user, &lock.asset, &ids);
+            }
+            env.storage().persistent().set(&key, &lock);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+        }
+        None => {
+            let Some(lock) = existing else {
+                return;
+            };
+            update_storage_stats(env, |stats| stats.persistent_entries -= 1);
+            env.storage().persistent().remove(&key);
+            let mut ids = get_lock_ids(env, &lock.user, &lock.asset);
+            if let Some(index) = ids.first_index_of(lock_id.clone()) {
+                ids.remove(index);
+            }
+            set_lock_ids(env, &lock.user, &lock.asset, &ids);
+        }
+    }
+}
+
+/// Protocol accounts (the admin and the fee recipient) are excluded from
+/// open interest
+fn is_protocol_account(env: &Env, user: &Address) -> bool {
+    user == &get_admin(env) || get_fee_recipient(env).as_ref() == Some(user)
+}
+
+/// Sum of all non-protocol vault balances for an asset
+pub fn get_open_interest(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::OpenInterest(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+fn adjust_open_interest(env: &Env, asset: &Address, delta: i128) {
+    if delta == 0 {
+        return;
+    }
+    let key = DataKey::OpenInterest(asset.clone());
+    let current: i128 = env.storage().instance().get(&key).unwrap_or(0);
+    env.storage().instance().set(&key, &(current + delta));
+}
+
+/// Change in the user/entry counters caused by balance writes
+#[derive(Default)]
+pub struct StatsDelta {
+    users: i32,
+    entries: i32,            // Balance keys (instance)
+    persistent_entries: i32, // NonZeroBalances keys
+}
+
+impl StatsDelta {
+    fn merge(&mut self, other: &StatsDelta) {
+        self.users += other.users;
+        self.entries += other.entries;
+        self.persistent_entries += other.persistent_entries;
+    }
+
+    fn apply(&self, stats: &mut StorageStats) {
+        stats.users_with_balance = stats.users_with_balance.wrapping_add_signed(self.users);
+        stats.instance_entries = stats.instance_entries.wrapping_add_signed(self.entries);
+        stats.persistent_entries = stats.persistent_entries.wrapping_add_signed(self.persistent_entries);
+    }
+}
+
+/// Write a balance whose previous value is already known
+/// Zero balances are removed so that a Balance key exists only while nonzero,
+/// which keeps the storage statistics exact. The caller applies the returned
+/// counter change, so an operation touching several balances updates the
+/// statistics entry once
+fn write_balance(env: &Env, user: &Address, asset: &Address, current: i128, amount: i128) -> StatsDelta {
+    let key = DataKey::Balance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    if amount != 0 {
+        env.storage().instance().set(&key, &amount);
+    } else {
+        env.storage().instance().remove(&key);
+    }
+
+    if current == 0 && amount != 0 {
+        track_nonzero_balance(env, user, true)
+    } else if current != 0 && amount == 0 {
+        track_nonzero_balance(env, user, false)
+    } else {
+        StatsDelta::default()
+    }
+}
+
+/// Maintain the per-user count of nonzero balances
+fn track_nonzero_balance(env: &Env, user: &Address, added: bool) -> StatsDelta {
+    let key = DataKey::NonZeroBalances(user.clone());
+    let count: u32 = env.storage().persistent().get(&key).unwrap_or(0);
+    let new_count = if added { count + 1 } else { count - 1 };
+    if new_count != 0 {
+        env.storage().persistent().set(&key, &new_count);
+        env.storage()
+            .persistent()
+            .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    } else {
+        env.storage().persistent().remove(&key);
+    }
+
+    // Balance key, plus the NonZeroBalances key when the user appears or disappears
+    if added {
+        let first = if count == 0 { 1 } else { 0 };
+        StatsDelta { users: first, entries: 1, persistent_entries: first }
+    } else {
+        let last = if new_count == 0 { 1 } else { 0 };
+        StatsDelta { users: -last, entries: -1, persistent_entries: -last }
+    }
+}
+
+fn apply_stats_delta(env: &Env, delta: &StatsDelta) {
+    if delta.users == 0 && delta.entries == 0 && delta.persistent_entries == 0 {
+        return;
+    }
+    update_storage_stats(env, |stats| delta.apply(stats));
+}
+
+/// Get the storage statistics counters
+pub fn get_storage_stats(env: &Env) -> StorageStats {
+    let mut stats = read_storage_stats(env);
+    // The sequence has its own counter; it is not duplicated in the stored stats
+    stats.latest_sequence = get_latest_sequence(env);
+    stats
+}
+
+fn read_storage_stats(env: &Env) -> StorageStats {
+    let key = DataKey::StorageStats;
+    env.storage().instance().get(&key).unwrap_or(StorageStats {
+        users_with_balance: 0,
+        settlement_count: 0,
+        latest_sequence: 0,
+        largest_history: 0,
+        instance_entries: 0,
+        persistent_entries: 0,
+    })
+}
+
+fn update_storage_stats(env: &Env, update: impl FnOnce(&mut StorageStats)) {
+    let mut stats = read_storage_stats(env);
+    update(&mut stats);
+    env.storage().instance().set(&DataKey::StorageStats, &stats);
+}
+
+/// Lifetime trading totals; fees come from the per-asset FeesCollected
+/// counters rather than being duplicated in the stored stats
+pub fn get_global_stats(env: &Env) -> GlobalStats {
+    let mut stats = read_global_stats(env);
+    for asset in get_supported_assets(env).iter() {
+        let fees = get_fees_collected(env, &asset);
+        stats.fees_collected.set(asset, fees);
+    }
+    stats
+}
+
+fn read_global_stats(env: &Env) -> GlobalStats {
+    let key = MarketDataKey::GlobalStats;
+    env.storage().instance().get(&key).unwrap_or_else(|| GlobalStats {
+        trade_count: 0,
+        base_volume: Map::new(env),
+        quote_volume: Map::new(env),
+        fees_collected: Map::new(env),
+        last_settlement: 0,
+    })
+}
+
+/// Count a successful settlement towards the lifetime totals
+fn record_global_stats(env: &Env, instruction: &SettlementInstruction) {
+    let mut stats = read_global_stats(env);
+    let base = &instruction.base_asset;
+    let quote = &instruction.quote_asset;
+    let base_volume = stats.base_volume.get(base.clone()).unwrap_or(0);
+    let quote_volume = stats.quote_volume.get(quote.clone()).unwrap_or(0);
+    stats.trade_count = stats.trade_count.saturating_add(1);
+    stats.base_volume.set(
+        base.clone(),
+        base_volume.saturating_add(instruction.base_amount),
+    );
+    stats.quote_volume.set(
+        quote.clone(),
+        quote_volume.saturating_add(instruction.quote_amount),
+    );
+    // The ledger clock only moves forward, unlike instruction timestamps
+    stats.last_settlement = env.ledger().timestamp();
+    env.storage().instance().set(&MarketDataKey::GlobalStats, &stats);
+}
+
+/// Add to user balance (deposit), returning the new balance
+pub fn add_balance(env: &Env, user: &Address, asset: &Address, amount: i128) -> i128 {
+    let current = get_balance(env, user, asset);
+    let new_balance = current + amount;
+    let delta = write_balance(env, user, asset, current, new_balance);
+    apply_stats_delta(env, &delta);
+    if !is_protocol_account(env, user) {
+        adjust_open_interest(env, asset, amount);
+    }
+    new_balance
+}
+
+/// Subtract from user balance (withdraw), returning the new balance
+pub fn subtract_balance(env: &Env, user: &Address, asset: &Address, amount: i128) -> i128 {
+    let current = get_balance(env, user, asset);
+    if current < amount {
+        panic!("Insufficient balance");
+    }
+    let new_balance = current - amount;
+    let delta = write_balance(env, user, asset, current, new_balance);
+    apply_stats_delta(env, &delta);
+    if !is_protocol_account(env, user) {
+        adjust_open_interest(env, asset, -amount);
+    }
+    new_balance
+}
+
+/// Append a deposit or withdrawal to the user's history under `key`,
+/// counting a newly created history in the stats. The history is a ring of
+/// VAULT_HISTORY_LENGTH entries, so its entry never outgrows the ledger
+/// limit and withdrawals cannot trap on it
+fn append_vault_movement(env: &Env, key: &UserDataKey, asset: &Address, amount: i128) {
+    let mut movements: Vec<VaultMovement> = env.storage().persistent().get(key).unwrap_or_else(|| Vec::new(env));
+    if movements.is_empty() {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    if movements.len() == VAULT_HISTORY_LENGTH {
+        movements.pop_front();
+    }
+    movements.push_back(VaultMovement {
+        asset: asset.clone(),
+        amount,
+        timestamp: env.ledger().timestamp(),
+    });
+    env.storage().persistent().set(key, &movements);
+    env.storage()
+        .persistent()
+        .extend_ttl(key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// The most recent `limit` entries of the history under `key`, oldest first
+fn recent_vault_movements(env: &Env, key: &UserDataKey, limit: u32) -> Vec<VaultMovement> {
+    let movements: Vec<VaultMovement> = env.storage().persistent().get(key).unwrap_or_else(|| Vec::new(env));
+    let len = movements.len();
+    movements.slice(len.saturating_sub(limit)..len)
+}
+
+pub fn record_deposit(env: &Env, user: &Address, asset: &Address, amount: i128) {
+    append_vault_movement(env, &UserDataKey::DepositHistory(user.clone()), asset, amount);
+    record_activity(env, user, ActivityKind::Deposit, None, &[(asset, amount)]);
+}
+
+pub fn record_withdrawal(env: &Env, user: &Address, asset: &Address, amount: i128) {
+    append_vault_movement(env, &UserDataKey::WithdrawHistory(user.clone()), asset, amount);
+    record_activity(env, user, ActivityKind::Withdrawal, None, &[(asset, -amount)]);
+}
+
+/// Append one journal entry per asset in `deltas` (summing repeats and
+/// skipping net zeros) to the user's activity journal. New entries go to
+/// the head page, which is archived under its page number once it holds
+/// ACTIVITY_PAGE_SIZE entries, so an append usually writes one entry
+pub fn record_activity(
+    env: &Env,
+    user: &Address,
+    kind: ActivityKind,
+    trade_id: Option<&BytesN<32>>,
+    deltas: &[(&Address, i128)],
+) {
+    let head_key = UserDataKey::ActivityHead(user.clone());
+    let existing: Option<Vec<JournalEntry>> = env.storage().persistent().get(&head_key);
+    let mut created_entries = if existing.is_none() { 1 } else { 0 };
+    let mut head = existing.unwrap_or_else(|| Vec::new(env));
+    let start = head.last().map_or(0, |entry| entry.sequence + 1);
+    let mut sequence = start;
+    for (i, (asset, _)) in deltas.iter().enumerate() {
+        if deltas[..i].iter().any(|(seen, _)| seen == asset) {
+            continue;
+        }
+        let amount: i128 = deltas.iter().filter(|(other, _)| other == asset).map(|(_, amount)| amount).sum();
+        if amount == 0 {
+            continue;
+        }
+        // A full head is archived as the page its entries belong to
+        if head.len() as u64 == ACTIVITY_PAGE_SIZE {
+            let key = UserDataKey::ActivityPage(user.clone(), sequence / ACTIVITY_PAGE_SIZE - 1);
+            env.storage().persistent().set(&key, &head);
+            env.storage()
+                .persistent()
+                .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+            created_entries += 1;
+            head = Vec::new(env);
+        }
+        head.push_back(JournalEntry {
+            kind,
+            asset: (*asset).clone(),
+            amount,
+            trade_id: trade_id.cloned(),
+            timestamp: env.ledger().timestamp(),
+            sequence,
+        });
+        sequence += 1;
+    }
+    if sequence == start {
+        return;
+    }
+    update_storage_stats(env, |stats| stats.persistent_entries += created_entries);
+    env.storage().persistent().set(&head_key, &head);
+    env.storage()
+        .persistent()
+        .extend_ttl(&head_key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Up to `limit` journal entries from sequence `cursor` (the first with
+/// None), oldest first, reading only the pages they sit in
+pub fn get_activity(env: &Env, user: &Address, cursor: Option<u64>, limit: u32) -> ActivityPage {
+    let head: Vec<JournalEntry> = env
+        .storage()
+        .persistent()
+        .get(&UserDataKey::ActivityHead(user.clone()))
+        .unwrap_or_else(|| Vec::new(env));
+    let count = head.last().map_or(0, |entry| entry.sequence + 1);
+    let head_start = head.first().map_or(0, |entry| entry.sequence);
+    let start = cursor.unwrap_or(0).min(count);
+    let end = start.saturating_add(limit as u64).min(count);
+
+    let mut entries = Vec::new(env);
+    let mut sequence = start;
+    while sequence < end {
+        let page_number = sequence / ACTIVITY_PAGE_SIZE;
+        let page_start = page_number * ACTIVITY_PAGE_SIZE;
+        let page: Vec<JournalEntry> = if page_start == head_start {
+            head.clone()
+        } else {
+            let key = UserDataKey::ActivityPage(user.clone(), page_number);
+            env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(env))
+        };
+        let page_end = (page_start + ACTIVITY_PAGE_SIZE).min(end);
+        entries.append(&page.slice((sequence - page_start) as u32..(page_end - page_start) as u32));
+        sequence = page_end;
+    }
+    ActivityPage {
+        entries,
+        next_cursor: (end < count).then_some(end),
+    }
+}
+
+pub fn get_deposit_history(env: &Env, user: &Address, limit: u32) -> Vec<VaultMovement> {
+    recent_vault_movements(env, &UserDataKey::DepositHistory(user.clone()), limit)
+}
+
+pub fn get_withdrawal_history(env: &Env, user: &Address, limit: u32) -> Vec<VaultMovement> {
+    recent_vault_movements(env, &UserDataKey::WithdrawHistory(user.clone()), limit)
+}
+
+/// Running sum of a user's signed vault movements in an asset: deposits,
+/// withdrawals and settlement deltas. It is written alongside, but never
+/// derived from, the balance, so the two can be reconciled
+pub fn get_net_flow(env: &Env, user: &Address, asset: &Address) -> i128 {
+    let key = DataKey::NetFlows(user.clone());
+    let net_flows: Map<Address, i128> = env.storage().persistent().get(&key).unwrap_or_else(|| Map::new(env));
+    net_flows.get(asset.clone()).unwrap_or(0)
+}
+
+/// Add signed vault movements, as (asset, delta) pairs, to a user's net
+/// flows. All of a user's assets share one entry, read and written once
+pub fn record_net_flows(env: &Env, user: &Address, deltas: &[(&Address, i128)]) {
+    let key = DataKey::NetFlows(user.clone());
+    let existing: Option<Map<Address, i128>> = env.storage().persistent().get(&key);
+    if existing.is_none() {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    let mut net_flows = existing.unwrap_or_else(|| Map::new(env));
+    for (asset, delta) in deltas {
+        let net_flow = net_flows.get((*asset).clone()).unwrap_or(0);
+        net_flows.set((*asset).clone(), net_flow + delta);
+    }
+    env.storage().persistent().set(&key, &net_flows);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Seed a user's net flows from their current balances, for balances
+/// written before net flows were recorded. Only supported assets with a
+/// nonzero balance and no recorded flow are seeded, so a recorded flow is
+/// never overwritten. Returns false if there was nothing to seed
+pub fn seed_user_net_flows(env: &Env, user: &Address) -> bool {
+    let key = DataKey::NetFlows(user.clone());
+    let existing: Option<Map<Address, i128>> = env.storage().persistent().get(&key);
+    let is_new = existing.is_none();
+    let mut net_flows = existing.unwrap_or_else(|| Map::new(env));
+    let mut seeded = false;
+    for asset in get_supported_assets(env) {
+        let balance = get_balance(env, user, &asset);
+        if balance != 0 && !net_flows.contains_key(asset.clone()) {
+            net_flows.set(asset, balance);
+            seeded = true;
+        }
+    }
+    if !seeded {
+        return false;
+    }
+    if is_new {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    env.storage().persistent().set(&key, &net_flows);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    true
+}
+
+/// Lifetime fees charged in an asset, regardless of later payouts
+pub fn get_fees_collected(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::FeesCollected(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Accrued protocol fees in an asset, segregated from vault balances
+pub fn get_fee_balance(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::FeeBalance(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Add `amount` (negative to debit) to an i128 accumulator in instance storage
+fn adjust_accumulator(env: &Env, key: &DataKey, amount: i128) {
+    let value: i128 = env.storage().instance().get(key).unwrap_or(0);
+    env.storage().instance().set(key, &(value + amount));
+}
+
+/// Add a settlement fee to the lifetime total, before it is split
+pub fn add_fees_collected(env: &Env, asset: &Address, amount: i128) {
+    adjust_accumulator(env, &DataKey::FeesCollected(asset.clone()), amount);
+}
+
+/// Lifetime maker rebates paid in an asset out of the taker fees counted
+/// in get_fees_collected
+pub fn get_maker_rebates(env: &Env, asset: &Address) -> i128 {
+    let key = MarketDataKey::MakerRebates(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Count a maker rebate paid out of a taker fee: the fee still counts in
+/// full as collected, and the rebate is tracked apart
+pub fn add_maker_rebate(env: &Env, asset: &Address, amount: i128) {
+    add_fees_collected(env, asset, amount);
+    let key = MarketDataKey::MakerRebates(asset.clone());
+    let rebates = get_maker_rebates(env, asset);
+    env.storage().instance().set(&key, &(rebates + amount));
+}
+
+/// Fees charged in an asset on one day (ledger timestamp / FEE_DAY_SECONDS)
+pub fn get_daily_fees(env: &Env, asset: &Address, day: u64) -> i128 {
+    let key = DataKey::DailyFees(day);
+    env.storage()
+        .persistent()
+        .get::<_, Map<Address, i128>>(&key)
+        .and_then(|fees| fees.get(asset.clone()))
+        .unwrap_or(0)
+}
+
+/// Add a settlement's fees to today's bucket
+/// All assets share one entry per day, so a settlement writes it once, and
+/// not at all without a positive fee
+pub fn add_daily_fees(env: &Env, fees: &[(&Address, i128)]) {
+    if fees.iter().all(|(_, amount)| *amount <= 0) {
+        return;
+    }
+    let key = DataKey::DailyFees(env.ledger().timestamp() / FEE_DAY_SECONDS);
+    let existing: Option<Map<Address, i128>> = env.storage().persistent().get(&key);
+    if existing.is_none() {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    let mut daily_fees = existing.unwrap_or_else(|| Map::new(env));
+    for (asset, amount) in fees.iter().filter(|(_, amount)| *amount > 0) {
+        let total = daily_fees.get((*asset).clone()).unwrap_or(0);
+        daily_fees.set((*asset).clone(), total + amount);
+    }
+    env.storage().persistent().set(&key, &daily_fees);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Lifetime fees paid out of the fee, insurance and referral balances
+pub fn get_fees_claimed(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::FeesClaimed(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+pub fn add_fees_claimed(env: &Env, asset: &Address, amount: i128) {
+    adjust_accumulator(env, &DataKey::FeesClaimed(asset.clone()), amount);
+}
+
+/// Reward per settled item paid to batch keepers in an asset (0 = none)
+pub fn get_keeper_reward(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::KeeperReward(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Set the keeper reward for an asset; 0 removes it
+pub fn set_keeper_reward(env: &Env, asset: &Address, amount: i128) {
+    let key = DataKey::KeeperReward(asset.clone());
+    if amount == 0 {
+        env.storage().instance().remove(&key);
+    } else {
+        env.storage().instance().set(&key, &amount);
+    }
+}
+
+/// Credit the protocol's share of a settlement fee
+pub fn credit_fee(env: &Env, asset: &Address, amount: i128) {
+    adjust_accumulator(env, &DataKey::FeeBalance(asset.clone()), amount);
+}
+
+/// Debit a payout from the protocol fee balance
+/// The lifetime total is left untouched
+pub fn debit_fee(env: &Env, asset: &Address, amount: i128) {
+    adjust_accumulator(env, &DataKey::FeeBalance(asset.clone()), -amount);
+}
+
+pub fn get_fee_split(env: &Env) -> FeeSplit {
+    let key = DataKey::FeeSplit;
+    env.storage().instance().get(&key).unwrap_or_default()
+}
+
+pub fn set_fee_split(env: &Env, split: &FeeSplit) {
+    let key = DataKey::FeeSplit;
+    env.storage().instance().set(&key, split);
+}
+
+pub fn get_insurance_admin(env: &Env) -> Option<Address> {
+    let key = DataKey::InsuranceAdmin;
+    env.storage().instance().get(&key)
+}
+
+pub fn set_insurance_admin(env: &Env, insurance_admin: &Address) {
+    let key = DataKey::InsuranceAdmin;
+    env.storage().instance().set(&key, insurance_admin);
+}
+
+/// Accrued insurance fund share of fees in an asset
+pub fn get_insurance_balance(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::InsuranceBalance(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Add to (or, with a negative amount, pay out of) the insurance fund balance
+pub fn adjust_insurance_balance(env: &Env, asset: &Address, amount: i128) {
+    adjust_accumulator(env, &DataKey::InsuranceBalance(asset.clone()), amount);
+}
+
+pub fn get_referrer(env: &Env, user: &Address) -> Option<Address> {
+    let key = DataKey::Referrer(user.clone());
+    env.storage().persistent().get(&key)
+}
+
+/// Record who referred a user, counting the new key in the stats
+pub fn set_referrer(env: &Env, user: &Address, referrer: &Address) {
+    let key = DataKey::Referrer(user.clone());
+    update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    env.storage().persistent().set(&key, referrer);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Accrued referral share of fees owed to a referrer in an asset
+pub fn get_referral_balance(env: &Env, referrer: &Address, asset: &Address) -> i128 {
+    let key = DataKey::ReferrerBalance(BalanceDataKey {
+        user: referrer.clone(),
+        asset: asset.clone(),
+    });
+    env.storage().persistent().get(&key).unwrap_or(0)
+}
+
+/// Add to (or, with a negative amount, pay out of) a referrer's balance,
+/// counting newly created keys in the stats
+pub fn adjust_referral_balance(env: &Env, referrer: &Address, asset: &Address, amount: i128) {
+    let key = DataKey::ReferrerBalance(BalanceDataKey {
+        user: referrer.clone(),
+        asset: asset.clone(),
+    });
+    let balance: Option<i128> = env.storage().persistent().get(&key);
+    if balance.is_none() {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    env.storage().persistent().set(&key, &(balance.unwrap_or(0) + amount));
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    adjust_accumulator(env, &DataKey::ReferralTotal(asset.clone()), amount);
+}
+
+/// Sum of every referrer's unclaimed balance in an asset
+pub fn get_referral_total(env: &Env, asset: &Address) -> i128 {
+    let key = DataKey::ReferralTotal(asset.clone());
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Sum of every vault balance in an asset, the protocol accounts' included,
+/// from the open interest counter rather than by iterating balances
+pub fn get_vault_total(env: &Env, asset: &Address) -> i128 {
+    let admin = get_admin(env);
+    let recipient = get_fee_recipient(env).filter(|recipient| recipient != &admin);
+    let recipient_balance = recipient.map_or(0, |recipient| get_balance(env, &recipient, asset));
+    get_open_interest(env, asset) + get_balance(env, &admin, asset) + recipient_balance
+}
+
+/// Everything the vault owes in an asset: user and admin vault balances plus
+/// the unclaimed protocol fee, insurance and referral balances
+pub fn get_liability(env: &Env, asset: &Address) -> i128 {
+    get_vault_total(env, asset)
+        + get_fee_balance(env, asset)
+        + get_insurance_balance(env, asset)
+        + get_referral_total(env, asset)
+}
+
+/// Remaining amount the matching engine may draw from a user's asset balance
+/// `None` means the user has not set a limit
+pub fn get_engine_allowance(env: &Env, user: &Address, asset: &Address) -> Option<i128> {
+    let key = DataKey::EngineAllowance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    env.storage().persistent().get(&key)
+}
+
+/// Set a user's engine allowance, counting newly created keys in the stats
+pub fn set_engine_allowance(env: &Env, user: &Address, asset: &Address, amount: i128) {
+    let key = DataKey::EngineAllowance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    if !env.storage().persistent().has(&key) {
+        update_storage_stats(env, |stats| stats.persistent_entries += 1);
+    }
+    env.storage().persistent().set(&key, &amount);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Overwrite an existing engine allowance after a settlement draws from it
+pub fn update_engine_allowance(env: &Env, user: &Address, asset: &Address, amount: i128) {
+    let key = DataKey::EngineAllowance(BalanceDataKey {
+        user: user.clone(),
+        asset: asset.clone(),
+    });
+    env.storage().persistent().set(&key, &amount);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Get the sequence number of the most recent settlement (0 if none)
+pub fn get_latest_sequence(env: &Env) -> u64 {
+    let key = DataKey::TradeSeq;
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+/// Assign the next global settlement sequence number
+fn next_sequence(env: &Env) -> u64 {
+    let sequence = get_latest_sequence(env) + 1;
+    env.storage().instance().set(&DataKey::TradeSeq, &sequence);
+    sequence
+}
+
+/// Maximum number of distinct (user, asset) entries a BalanceBatch can hold
+const BALANCE_BATCH_CAPACITY: usize = 8;
+
+/// Balance changes for a single operation, batched so that each (user, asset)
+/// entry is read at most once and written at most once on commit.
+/// Aliased accounts (e.g. the admin trading against itself) merge naturally.
+/// Entries live in a plain array so bookkeeping costs no host calls.
+#[derive(Default)]
+pub struct BalanceBatch {
+    entries: [Option<(Address, Address, i128, i128)>; BALANCE_BATCH_CAPACITY], // user, asset, original, current
+}
+
+impl BalanceBatch {
+    /// Index of the entry for (user, asset), loading it from storage on first use
+    fn index(&mut self, env: &Env, user: &Address, asset: &Address) -> usize {
+        for i in 0..BALANCE_BATCH_CAPACITY {
+            match &self.entries[i] {
+                Some((u, a, _, _)) if u == user && a == asset => return i,
+                Some(_) => continue,
+                None => {
+                    let balance = get_balance(env, user, asset);
+                    self.entries[i] = Some((user.clone(), asset.clone(), balance, balance));
+                    return i;
+                }
+            }
+        }
+        panic!("Balance batch full");
+    }
+
+    /// Current (uncommitted) balance
+    pub fn get(&mut self, env: &Env, user: &Address, asset: &Address) -> i128 {
+        let i = self.index(env, user, asset);
+        self.entries[i].as_ref().unwrap().3
+    }
+
+    pub fn add(&mut self, env: &Env, user: &Address, asset: &Address, amount: i128) {
+        let i = self.index(env, user, asset);
+        let entry = self.entries[i].as_mut().unwrap();
+        entry.3 += amount;
+    }
+
+    pub fn subtract(&mut self, env: &Env, user: &Address, asset: &Address, amount: i128) {
+        let i = self.index(env, user, asset);
+        let entry = self.entries[i].as_mut().unwrap();
+        if entry.3 < amount {
+            panic!("Insufficient balance");
+        }
+        entry.3 -= amount;
+    }
+
+    /// Write every changed balance back to storage, updating open interest
+    /// once per asset. Returns the storage statistics change for the caller to apply
+    pub fn commit(&self, env: &Env) -> StatsDelta {
+        let admin = get_admin(env);
+        let fee_recipient = get_fee_recipient(env);
+        let mut delta = StatsDelta::default();
+        let mut open_interest: [Option<(Address, i128)>; BALANCE_BATCH_CAPACITY] = Default::default();
+        for (user, asset, original, current) in self.entries.iter().flatten() {
+            if current == original {
+                continue;
+            }
+            delta.merge(&write_balance(env, user, asset, *original, *current));
+            if user == &admin || fee_recipient.as_ref() == Some(user) {
+                continue;
+            }
+            for slot in open_interest.iter_mut() {
+                match slot {
+                    Some((a, change)) if a == asset => {
+                        *change += current - original;
+                        break;
+                    }
+                    Some(_) => continue,
+                    None => {
+                        *slot = Some((asset.clone(), current - original));
+                        break;
+                    }
+                }
+            }
+        }
+        for (asset, change) in open_interest.iter().flatten() {
+            adjust_open_interest(env, asset, *change);
+        }
+        delta
+    }
+}
+
+/// Record a settlement, folding the balance statistics change from the same
+/// trade into a single update of the statistics entry
+pub fn record_settlement(
+    env: &Env,
+    instruction: &SettlementInstruction,
+    (execution_price, execution_quantity): (i128, i128),
+    fee_base: i128,
+    fee_quote: i128,
+    maker_rebate: i128,
+    balance_delta: &StatsDelta,
+) -> SettlementRecord {
+    let sequence = next_sequence(env);
+    let record = SettlementRecord {
+        trade_id: instruction.trade_id.clone(),
+        buy_user: instruction.buy_user.clone(),
+        sell_user: instruction.sell_user.clone(),
+        base_asset: instruction.base_asset.clone(),
+        quote_asset: instruction.quote_asset.clone(),
+        base_amount: instruction.base_amount,
+        quote_amount: instruction.quote_amount,
+        execution_price,
+        execution_quantity,
+        fee_base,
+        fee_quote,
+        maker_rebate,
+        fee_asset: instruction.fee_asset.clone(),
+        fee_amount: instruction.fee_amount,
+        referrer: instruction.referrer.clone(),
+        taker_side: instruction.taker_side,
+        timestamp: instruction.timestamp,
+        sequence,
+    };
+
+    // Store by trade ID and in the global sequence index in one pass
+    let trade_key = DataKey::Settlement(instruction.trade_id.clone());
+    let is_new_record = !env.storage().instance().has(&trade_key);
+    env.storage().instance().set(&trade_key, &record);
+    let sequence_key = DataKey::SequenceIndex(sequence);
+    env.storage().persistent().set(&sequence_key, &instruction.trade_id);
+    env.storage()
+        .persistent()
+        .extend_ttl(&sequence_key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+
+    // Append to each participant's history, reading and writing each key once
+    // (settle_trade rejects self-trades, so the two users are distinct)
+    let (buy_len, buy_created) = append_user_trade_id(env, &instruction.buy_user, &instruction.trade_id, Side::Buy);
+    let (sell_len, sell_created) = append_user_trade_id(env, &instruction.sell_user, &instruction.trade_id, Side::Sell);
+    let pair = pair_key(env, &instruction.base_asset, &instruction.quote_asset);
+    let (base_amount, quote_amount) = canonical_amounts(&pair, instruction);
+    let new_pair_day = add_daily_volume(env, &pair, quote_amount);
+    let new_pair_hour = add_hourly_volume(env, &pair, base_amount, quote_amount);
+    let settled_at = env.ledger().timestamp();
+    let new_day = append_day_settlement(env, settled_at / FEE_DAY_SECONDS, sequence, settled_at);
+    let buy_first = record_user_trade(env, &instruction.buy_user, instruction.timestamp);
+    let sell_first = record_user_trade(env, &instruction.sell_user, instruction.timestamp);
+
+    record_global_stats(env, instruction);
+    let new_pair_stats = record_pair_stats(env, &pair, base_amount, quote_amount, instruction.timestamp);
+    set_last_price(env, &pair, instruction, execution_price);
+    // Largest-trade entries keep their own copy, so they survive the record
+    let trade = LargestTrade {
+        trade_id: instruction.trade_id.clone(),
+        base_asset: pair.base.clone(),
+        quote_asset: pair.quote.clone(),
+        base_amount,
+        quote_amount,
+        timestamp: instruction.timestamp,
+    };
+    let new_largest = record_largest_trade(env, &MarketDataKey::PairLargestTrade(pair.clone()), &trade) as u32
+        + record_largest_trade(env, &MarketDataKey::LargestTrade, &trade) as u32;
+
+    update_storage_stats(env, |stats| {
+        balance_delta.apply(stats);
+        if is_new_record {
+            stats.settlement_count += 1;
+            stats.instance_entries += 1; // Settlement key
+        }
+        stats.instance_entries += 2 * new_pair_stats as u32; // First trade in the pair: its stats and last price
+        stats.persistent_entries += buy_created + sell_created; // New history heads and archived pages
+        stats.persistent_entries += buy_first as u32 + sell_first as u32; // New trade stats
+        stats.persistent_entries += new_day + new_pair_day as u32; // New day buckets and index pages
+        stats.persistent_entries += new_pair_hour as u32; // New hourly VWAP bucket
+        stats.persistent_entries += new_largest; // First largest trade in the pair, or anywhere
+        stats.persistent_entries += 1; // SequenceIndex key
+        stats.largest_history = stats.largest_history.max(buy_len).max(sell_len);
+    });
+
+    record
+}
+
+/// The (base, quote) amounts a settlement moved, oriented as its canonical pair
+pub fn canonical_amounts(pair: &AssetPairKey, instruction: &SettlementInstruction) -> (i128, i128) {
+    if pair.quote == instruction.quote_asset {
+        (instruction.base_amount, instruction.quote_amount)
+    } else {
+        (instruction.quote_amount, instruction.base_amount)
+    }
+}
+
+/// Volume settled in a pair on `day` (ledger timestamp / FEE_DAY_SECONDS);
+/// zero for a day without trades
+pub fn get_daily_volume(env: &Env, pair: &AssetPairKey, day: u64) -> DailyVolume {
+    let key = MarketDataKey::DailyVolume(pair.clone(), day);
+    env.storage().persistent().get(&key).unwrap_or(DailyVolume {
+        day,
+        quote_volume: 0,
+        trade_count: 0,
+    })
+}
+
+/// Add a settlement to today's bucket for its pair, returning whether the
+/// bucket is new
+fn add_daily_volume(env: &Env, pair: &AssetPairKey, quote_amount: i128) -> bool {
+    let day = env.ledger().timestamp() / FEE_DAY_SECONDS;
+    let key = MarketDataKey::DailyVolume(pair.clone(), day);
+    let mut volume = get_daily_volume(env, pair, day);
+    let is_new = volume.trade_count == 0;
+    volume.quote_volume = volume.quote_volume.saturating_add(quote_amount);
+    volume.trade_count += 1;
+    env.storage().persistent().set(&key, &volume);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    is_new
+}
+
+/// Base and quote amounts settled in a pair during `hour` (ledger timestamp
+/// / HOUR_SECONDS), in its canonical orientation; zero for an hour without trades
+pub fn get_hourly_volume(env: &Env, pair: &AssetPairKey, hour: u64) -> (i128, i128) {
+    let key = MarketDataKey::HourlyVolume(pair.clone(), hour);
+    env.storage().persistent().get(&key).unwrap_or((0, 0))
+}
+
+/// Add a settlement to the current hour's VWAP accumulators for its pair,
+/// returning whether the bucket is new
+fn add_hourly_volume(env: &Env, pair: &AssetPairKey, base_amount: i128, quote_amount: i128) -> bool {
+    let hour = env.ledger().timestamp() / HOUR_SECONDS;
+    let key = MarketDataKey::HourlyVolume(pair.clone(), hour);
+    let existing: Option<(i128, i128)> = env.storage().persistent().get(&key);
+    let (base_total, quote_total) = existing.unwrap_or((0, 0));
+    let totals = (
+        base_total.saturating_add(base_amount),
+        quote_total.saturating_add(quote_amount),
+    );
+    env.storage().persistent().set(&key, &totals);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    existing.is_none()
+}
+
+/// Lifetime totals for a pair; all zero before its first trade
+pub fn get_pair_stats(env: &Env, pair: &AssetPairKey) -> PairStats {
+    let key = MarketDataKey::PairStats(pair.clone());
+    env.storage().instance().get(&key).unwrap_or_default()
+}
+
+/// Add a settlement to its pair's lifetime totals, returning whether it is
+/// the pair's first
+fn record_pair_stats(env: &Env, pair: &AssetPairKey, base_amount: i128, quote_amount: i128, timestamp: u64) -> bool {
+    let mut stats = get_pair_stats(env, pair);
+    let is_new = stats.trade_count == 0;
+    stats.trade_count = stats.trade_count.saturating_add(1);
+    stats.base_volume = stats.base_volume.saturating_add(base_amount);
+    stats.quote_volume = stats.quote_volume.saturating_add(quote_amount);
+    stats.last_trade = timestamp;
+    env.storage().instance().set(&MarketDataKey::PairStats(pair.clone()), &stats);
+    is_new
+}
+
+/// Latest execution price in a pair, as canonical quote per base scaled by
+/// the price scale; None before its first trade
+pub fn get_last_price(env: &Env, pair: &AssetPairKey) -> Option<i128> {
+    let key = MarketDataKey::LastPrice(pair.clone());
+    env.storage().instance().get(&key)
+}
+
+/// Store a settlement's execution price as its pair's last price, inverted
+/// when the instruction lists the pair's assets the other way round (0 if a
+/// price rounded down to 0 cannot be inverted)
+fn set_last_price(env: &Env, pair: &AssetPairKey, instruction: &SettlementInstruction, execution_price: i128) {
+    let price = if pair.base == instruction.base_asset {
+        execution_price
+    } else {
+        let scale = get_price_scale(env);
+        scale.checked_mul(scale).expect("Price overflow").checked_div(execution_price).unwrap_or(0)
+    };
+    env.storage().instance().set(&MarketDataKey::LastPrice(pair.clone()), &price);
+}
+
+/// Largest settlement by quote notional stored under `key`, if any
+pub fn get_largest_trade(env: &Env, key: &MarketDataKey) -> Option<LargestTrade> {
+    env.storage().persistent().get(key)
+}
+
+/// Replace the largest trade under `key` if `trade` is larger (ties keep the
+/// earlier trade), returning whether this created the entry
+fn record_largest_trade(env: &Env, key: &MarketDataKey, trade: &LargestTrade) -> bool {
+    let current = get_largest_trade(env, key);
+    if current.as_ref().is_some_and(|current| trade.quote_amount <= current.quote_amount) {
+        return false;
+    }
+    env.storage().persistent().set(key, trade);
+    env.storage()
+        .persistent()
+        .extend_ttl(key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    current.is_none()
+}
+
+/// Smallest base volume a VWAP window needs before get_vwap reports a price
+pub fn get_vwap_min_volume(env: &Env) -> i128 {
+    let key = MarketDataKey::VwapMinVolume;
+    env.storage().instance().get(&key).unwrap_or(0)
+}
+
+pub fn set_vwap_min_volume(env: &Env, min_volume: i128) {
+    let key = MarketDataKey::VwapMinVolume;
+    env.storage().instance().set(&key, &min_volume);
+}
+
+/// The settlement index of `day` (ledger timestamp / FEE_DAY_SECONDS, the
+/// clock DailyVolume and the daily fee buckets use): how many
+/// full pages were archived, and the newest (sequence, timestamp) entries,
+/// ascending
+pub fn get_day_settlements(env: &Env, day: u64) -> (u32, Vec<(u64, u64)>) {
+    let key = DataKey::SettlementsByDay(day);
+    env.storage()
+        .persistent()
+        .get(&key)
+        .unwrap_or_else(|| (0, Vec::new(env)))
+}
+
+/// One archived page of a day's settlement index, ascending
+pub fn get_day_settlement_page(env: &Env, day: u64, page: u32) -> Vec<(u64, u64)> {
+    let key = MarketDataKey::DaySettlementPage(day, page);
+    env.storage()
+        .persistent()
+        .get(&key)
+        .unwrap_or_else(|| Vec::new(env))
+}
+
+/// Add a settlement to its day's index, returning how many entries were
+/// created. Like the activity journal, a full head is archived as a page,
+/// so a busy day never grows a single entry without bound
+fn append_day_settlement(env: &Env, day: u64, sequence: u64, timestamp: u64) -> u32 {
+    let key = DataKey::SettlementsByDay(day);
+    let existing: Option<(u32, Vec<(u64, u64)>)> = env.storage().persistent().get(&key);
+    let mut created = if existing.is_none() { 1 } else { 0 };
+    let (mut pages, mut head) = existing.unwrap_or_else(|| (0, Vec::new(env)));
+    if head.len() as u64 == ACTIVITY_PAGE_SIZE {
+        let page_key = MarketDataKey::DaySettlementPage(day, pages);
+        env.storage().persistent().set(&page_key, &head);
+        env.storage()
+            .persistent()
+            .extend_ttl(&page_key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+        created += 1;
+        pages += 1;
+        head = Vec::new(env);
+    }
+    head.push_back((sequence, timestamp));
+    env.storage().persistent().set(&key, &(pages, head));
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    created
+}
+
+/// Whether a trade ID has already been settled
+pub fn is_settled(env: &Env, trade_id: &BytesN<32>) -> bool {
+    env.storage().instance().has(&DataKey::Settlement(trade_id.clone()))
+}
+
+pub fn get_settlement(env: &Env, trade_id: &BytesN<32>) -> Option<SettlementRecord> {
+    let key = DataKey::Settlement(trade_id.clone());
+    env.storage().instance().get(&key)
+}
+
+pub fn get_settlements(env: &Env, trade_ids: &Vec<BytesN<32>>) -> Vec<Option<SettlementRecord>> {
+    let mut records = Vec::new(env);
+    for trade_id in trade_ids.iter() {
+        records.push_back(get_settlement(env, &trade_id));
+    }
+    records
+}
+
+pub fn get_settlement_by_sequence(env: &Env, sequence: u64) -> Option<SettlementRecord> {
+    let key = DataKey::SequenceIndex(sequence);
+    let trade_id: Option<BytesN<32>> = env.storage().persistent().get(&key);
+    trade_id.and_then(|trade_id| get_settlement(env, &trade_id))
+}
+
+/// Up to `limit` records with sequence numbers in [from_seq, to_seq),
+/// oldest first, skipping sequences whose index entry or record is gone
+pub fn get_settlement_range(env: &Env, from_seq: u64, to_seq: u64, limit: u32) -> Vec<SettlementRecord> {
+    let mut records = Vec::new(env);
+    let end = to_seq.min(get_latest_sequence(env) + 1);
+    for sequence in from_seq.max(1)..end {
+        if records.len() == limit {
+            break;
+        }
+        if let Some(record) = get_settlement_by_sequence(env, sequence) {
+            records.push_back(record);
+        }
+    }
+    records
+}
+
+const TRADE_PAGE_SIZE: u32 = ACTIVITY_PAGE_SIZE as u32;
+
+/// A user's trade history, oldest first, read by index as (trade id, side
+/// the user took). Like the activity journal, the newest entries sit in a
+/// head entry and older ones in archived pages of ACTIVITY_PAGE_SIZE, so
+/// settling rewrites only the head however long the history grows, and
+/// readers load only the pages they reach
+struct TradeIds<'a> {
+    env: &'a Env,
+    user: &'a Address,
+    pages: u32,
+    head: Vec<(BytesN<32>, Side)>,
+    page: Option<(u32, Vec<(BytesN<32>, Side)>)>, // Last archived page read
+}
+
+impl<'a> TradeIds<'a> {
+    fn load(env: &'a Env, user: &'a Address) -> Self {
+        let (pages, head) = get_trade_history_head(env, user).unwrap_or_else(|| (0, Vec::new(env)));
+        TradeIds { env, user, pages, head, page: None }
+    }
+
+    fn len(&self) -> u32 {
+        self.pages * TRADE_PAGE_SIZE + self.head.len()
+    }
+
+    fn get(&mut self, index: u32) -> (BytesN<32>, Side) {
+        let page_number = index / TRADE_PAGE_SIZE;
+        if page_number == self.pages {
+            return self.head.get_unchecked(index % TRADE_PAGE_SIZE);
+        }
+        if self.page.as_ref().map(|(number, _)| *number) != Some(page_number) {
+            let key = UserDataKey::TradeHistoryPage(self.user.clone(), page_number);
+            let page = self.env.storage().persistent().get(&key).unwrap_or_else(|| Vec::new(self.env));
+            self.page = Some((page_number, page));
+        }
+        self.page.as_ref().unwrap().1.get_unchecked(index % TRADE_PAGE_SIZE)
+    }
+}
+
+fn get_trade_history_head(env: &Env, user: &Address) -> Option<(u32, Vec<(BytesN<32>, Side)>)> {
+    env.storage().persistent().get(&UserDataKey::TradeHistoryHead(user.clone()))
+}
+
+/// A user's (trade count, earliest, latest settlement timestamp)
+pub fn get_user_trade_stats(env: &Env, user: &Address) -> Option<(u32, u64, u64)> {
+    let key = DataKey::UserTradeStats(user.clone());
+    env.storage().persistent().get(&key)
+}
+
+/// Count a trade in the user's trade stats, returning whether it was the
+/// user's first (creating the entry)
+fn record_user_trade(env: &Env, user: &Address, timestamp: u64) -> bool {
+    let key = DataKey::UserTradeStats(user.clone());
+    let stats = match get_user_trade_stats(env, user) {
+        Some((count, first, last)) => (count.saturating_add(1), first.min(timestamp), last.max(timestamp)),
+        None => (1, timestamp, timestamp),
+    };
+    env.storage().persistent().set(&key, &stats);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+    stats.0 == 1
+}
+
+/// Get a user's trades, newest first, optionally only those on one side
+/// `offset` skips that many of the most recent matching trades. Each history
+/// entry is tagged with the user's side when it is written, so records on
+/// the other side are never loaded and settlements write nothing extra
+pub fn get_trade_history_filtered(
+    env: &Env,
+    user: &Address,
+    side: Option<Side>,
+    offset: u32,
+    limit: u32,
+) -> Vec<SettlementRecord> {
+    let mut trade_ids = TradeIds::load(env, user);
+
+    // Walk back from the newest trade so only the needed records are loaded
+    let mut records = Vec::new(env);
+    let mut skipped = 0;
+    for i in (0..trade_ids.len()).rev() {
+        if records.len() >= limit {
+            break;
+        }
+        let (trade_id, user_side) = trade_ids.get(i);
+        if side.is_some_and(|side| side != user_side) {
+            continue;
+        }
+        let Some(record) = get_settlement(env, &trade_id) else {
+            continue;
+        };
+        if skipped < offset {
+            skipped += 1;
+        } else {
+            records.push_back(record);
+        }
+    }
+    records
+}
+
+/// Up to `limit` of a user's trades older than history index `cursor` (from
+/// the newest with None), newest first. Histories only grow at the end, so
+/// indices stay put and pages resume exactly where the last one stopped
+pub fn get_trade_history_page(env: &Env, user: &Address, cursor: Option<u64>, limit: u32) -> TradeHistoryPage {
+    let mut trade_ids = TradeIds::load(env, user);
+    let len = trade_ids.len();
+    let end = cursor.map_or(len, |cursor| cursor.min(len as u64) as u32);
+    let start = end.saturating_sub(limit);
+
+    let mut records = Vec::new(env);
+    for i in (start..end).rev() {
+        if let Some(record) = get_settlement(env, &trade_ids.get(i).0) {
+            records.push_back(record);
+        }
+    }
+    TradeHistoryPage {
+        records,
+        next_cursor: (start > 0).then_some(start as u64),
+    }
+}
+
+/// Append a trade ID, tagged with the side the user took, to a user's
+/// history, returning the new history length
+/// and how many entries were created. A full head is archived as a page
+/// first, so the append writes one entry (two every ACTIVITY_PAGE_SIZE trades)
+fn append_user_trade_id(env: &Env, user: &Address, trade_id: &BytesN<32>, side: Side) -> (u32, u32) {
+    let existing = get_trade_history_head(env, user);
+    let mut created = existing.is_none() as u32;
+    let (mut pages, mut head) = existing.unwrap_or_else(|| (0, Vec::new(env)));
+    if head.len() == TRADE_PAGE_SIZE {
+        set_trade_id_page(env, user, pages, &head);
+        created += 1;
+        pages += 1;
+        head = Vec::new(env);
+    }
+    head.push_back((trade_id.clone(), side));
+    set_trade_history_head(env, user, pages, &head);
+    (pages * TRADE_PAGE_SIZE + head.len(), created)
+}
+
+fn set_trade_history_head(env: &Env, user: &Address, pages: u32, head: &Vec<(BytesN<32>, Side)>) {
+    let key = UserDataKey::TradeHistoryHead(user.clone());
+    env.storage().persistent().set(&key, &(pages, head.clone()));
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+fn set_trade_id_page(env: &Env, user: &Address, page: u32, trade_ids: &Vec<(BytesN<32>, Side)>) {
+    let key = UserDataKey::TradeHistoryPage(user.clone(), page);
+    env.storage().persistent().set(&key, trade_ids);
+    env.storage()
+        .persistent()
+        .extend_ttl(&key, PERSISTENT_LIFETIME_THRESHOLD, PERSISTENT_BUMP_AMOUNT);
+}
+
+/// Move a user's trade history written as a single list by earlier versions
+/// (in instance storage, or later in one persistent entry) into the paged
+/// history, ahead of any trades recorded since the upgrade. Each legacy id
+/// is tagged with the user's side from its record; ids without a record
+/// are dropped, as no query could return them.
+/// Returns false if there was nothing to migrate
+pub fn migrate_user_trade_history(env: &Env, user: &Address) -> bool {
+    let key = DataKey::UserTradeHistory(user.clone());
+    let in_instance: Option<Vec<BytesN<32>>> = env.storage().instance().get(&key);
+    let in_persistent: Option<Vec<BytesN<32>>> = env.storage().persistent().get(&key);
+    if in_instance.is_none() && in_persistent.is_none() {
+        return false;
+    }
+    let mut trade_ids = Vec::new(env);
+    for legacy in [&in_instance, &in_persistent].into_iter().flatten() {
+        for trade_id in legacy.iter() {
+            if let Some(record) = get_settlement(env, &trade_id) {
+                let side = if &record.buy_user == user { Side::Buy } else { Side::Sell };
+                trade_ids.push_back((trade_id, side));
+            }
+        }
+    }
+    let mut paged = TradeIds::load(env, user);
+    let old_entries = paged.pages + (paged.len() > 0) as u32;
+    for i in 0..paged.len() {
+        trade_ids.push_back(paged.get(i));
+    }
+
+    // Rewrite the whole history as pages and a non-empty head
+    let len = trade_ids.len();
+    let pages = len.saturating_sub(1) / TRADE_PAGE_SIZE;
+    for page in 0..pages {
+        let start = page * TRADE_PAGE_SIZE;
+        set_trade_id_page(env, user, page, &trade_ids.slice(start..start + TRADE_PAGE_SIZE));
+    }
+    if len > 0 {
+        set_trade_history_head(env, user, pages, &trade_ids.slice(pages * TRADE_PAGE_SIZE..len));
+    }
+    let new_entries = pages + (len > 0) as u32;
+    env.storage().instance().remove(&key);
+    env.storage().persistent().remove(&key);
+
+    // The legacy keys leave instance and persistent storage
+    update_storage_stats(env, |stats| {
+        stats.largest_history = stats.largest_history.max(len);
+        if in_instance.is_some() {
+            stats.instance_entries = stats.instance_entries.saturating_sub(1);
+        }
+        stats.persistent_entries += new_entries - old_entries;
+        if in_persistent.is_some() {
+            stats.persistent_entries = stats.persistent_entries.saturating_sub(1);
+        }
+    });
+    true
+}
+
+pub fn get_trade_history(env: &Env, user: &Address, limit: u32) -> Vec<SettlementRecord> {
+    let mut trade_ids = TradeIds::load(env, user);
+
+    let mut records = Vec::new(env);
+    let len = trade_ids.len();
+    let start = len.saturating_sub(limit);
+
+    for i in start..len {
+        if let Some(record) = get_settlement(env, &trade_ids.get(i).0) {
+            records.push_back(record);
+        }
+    }
+
+    records
+}
+
+/// Get the IDs of the user's most recent `limit` trades, oldest first,
+/// without loading their records
+pub fn get_trade_ids(env: &Env, user: &Address, limit: u32) -> Vec<BytesN<32>> {
+    let mut trade_ids = TradeIds::load(env, user);
+    let len = trade_ids.len();
+    let mut ids = Vec::new(env);
+    for i in len.saturating_sub(limit)..len {
+        ids.push_back(trade_ids.get(i).0);
+    }
+    ids
+}
+
+/// Get the user's most recent `limit` trades against `counterparty`, oldest first
+pub fn get_trades_with(env: &Env, user: &Address, counterparty: &Address, limit: u32) -> Vec<SettlementRecord> {
+    let mut trade_ids = TradeIds::load(env, user);
+
+    // Walk back from the newest trade so only the needed records are loaded
+    let mut records = Vec::new(env);
+    for i in (0..trade_ids.len()).rev() {
+        if records.len() >= limit {
+            break;
+        }
+        if let Some(record) = get_settlement(env, &trade_ids.get(i).0) {
+            let other = if &record.buy_user == user { &record.sell_user } else { &record.buy_user };
+            if other == counterparty {
+                records.push_front(record);
+            }
+        }
+    }
+
+    records
+}
+
+/// Summarize a user's trades, newest first, skipping the `offset` most recent
+pub fn get_trade_summaries(env: &Env, user: &Address, offset: u32, limit: u32) -> Vec<TradeSummary> {
+    let mut trade_ids = TradeIds::load(env, user);
+
+    let mut summaries = Vec::new(env);
+    let end = trade_ids.len().saturating_sub(offset);
+    let start = end.saturating_sub(limit);
+
+    for i in (start..end).rev() {
+        if let Some(record) = get_settlement(env, &trade_ids.get(i).0) {
+            summaries.push_back(user_summary(user, record));
+        }
+    }
+
+    summaries
+}
+
+fn user_summary(user: &Address, record: SettlementRecord) -> TradeSummary {
+    let side = if &record.buy_user == user { Side::Buy } else { Side::Sell };
+    TradeSummary {
+        trade_id: record.trade_id,
+        side,
+        price: record.execution_price,
+        base_amount: record.base_amount,
+        timestamp: record.timestamp,
+    }
+}
+
+/// Summaries of the `limit` most recent settlements across all users,
+/// newest first, walking the global sequence index. `side` is the taker's
+pub fn get_recent_settlements(env: &Env, limit: u32) -> Vec<TradeSummary> {
+    let latest = get_latest_sequence(env);
+    let start = latest.saturating_sub(limit as u64);
+
+    let mut summaries = Vec::new(env);
+    for sequence in (start + 1..=latest).rev() {
+        if let Some(record) = get_settlement_by_sequence(env, sequence) {
+            summaries.push_back(taker_summary(record));
+        }
+    }
+
+    summaries
+}
+
+/// Up to `limit` settlements older than sequence `cursor` (from the latest
+/// with None), newest first. Sequences never change, so pages resume
+/// exactly where the last one stopped however many trades settle between
+pub fn get_recent_settlements_page(env: &Env, cursor: Option<u64>, limit: u32) -> TradeSummaryPage {
+    let latest = get_latest_sequence(env);
+    let end = cursor.map_or(latest + 1, |cursor| cursor.min(latest + 1));
+    let start = end.saturating_sub(limit as u64).max(1);
+
+    let mut summaries = Vec::new(env);
+    for sequence in (start..end).rev() {
+        if let Some(record) = get_settlement_by_sequence(env, sequence) {
+            summaries.push_back(taker_summary(record));
+        }
+    }
+    TradeSummaryPage {
+        summaries,
+        next_cursor: (start > 1).then_some(start),
+    }
+}
+
+/// A settlement as the global feed shows it, from the taker's side
+fn taker_summary(record: SettlementRecord) -> TradeSummary {
+    TradeSummary {
+        trade_id: record.trade_id,
+        side: record.taker_side,
+        price: record.execution_price,
+        base_amount: record.base_amount,
+        timestamp: record.timestamp,
+    }
 }
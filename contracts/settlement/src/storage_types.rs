@@ -1,5 +1,14 @@
 use soroban_sdk::{contracttype, Address, BytesN};
 
+// Ledger TTL settings (~5s ledger close time)
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+// Instance entry (admin, assets, engine): bumped to 7 days whenever less than 6 remain
+pub(crate) const INSTANCE_BUMP_AMOUNT: u32 = 7 * DAY_IN_LEDGERS;
+pub(crate) const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+// Persistent entries (per-user data): bumped to 30 days whenever less than 29 remain
+pub(crate) const PERSISTENT_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+pub(crate) const PERSISTENT_LIFETIME_THRESHOLD: u32 = PERSISTENT_BUMP_AMOUNT - DAY_IN_LEDGERS;
+
 // Storage key for user balances (needs struct since it has two fields)
 #[derive(Clone)]
 #[contracttype]
@@ -8,15 +17,150 @@ pub struct BalanceDataKey {
     pub asset: Address,
 }
 
+// Storage key for a trading pair, canonicalized by storage::pair_key
+#[derive(Clone)]
+#[contracttype]
+pub struct AssetPairKey {
+    pub base: Address,
+    pub quote: Address,
+}
+
+// Length of a fee-tier volume epoch in seconds (30 days)
+pub(crate) const VOLUME_EPOCH_SECONDS: u64 = 30 * 86_400;
+
+// Length of a daily fee or settlement bucket in seconds
+pub(crate) const FEE_DAY_SECONDS: u64 = 86_400;
+
+// Length of an hourly VWAP bucket in seconds
+pub(crate) const HOUR_SECONDS: u64 = 3_600;
+
+// Default instruction size limits until the admin configures them
+pub(crate) const DEFAULT_MAX_MEMO_BYTES: u32 = 256;
+pub(crate) const DEFAULT_MAX_PROOF_LEN: u32 = 16;
+
 // Main storage key enum
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
     Admin,
+    AdminSigners,
+    AdminThreshold,
     MatchingEngine,
     AssetA,
     AssetB,
+    ExtraAssets,                       // supported assets registered after deployment
+    PriceScale,
     Balance(BalanceDataKey),
     Settlement(BytesN<32>),            // trade_id
-    UserTradeHistory(Address),         // user
+    UserTradeHistory(Address),         // user -> whole trade id list of earlier versions, until migrate_trade_history pages it
+    UserTradeStats(Address),           // user -> (trade count, first, last timestamp) (persistent)
+    TradeSeq,                          // latest assigned settlement sequence
+    SequenceIndex(u64),                // sequence -> trade_id (persistent)
+    SettlementsByDay(u64),             // ledger day -> (archived pages, newest (sequence, timestamp) entries) (persistent)
+    AssetPaused(Address),              // asset
+    NonZeroBalances(Address),          // user -> number of assets with a nonzero balance (persistent)
+    StorageStats,
+    OpenInterest(Address),             // asset -> sum of non-protocol balances
+    SettlementMode,
+    FeeMode,
+    Initialized,
+    ImportClosed,
+    InstructionLimits,
+    FeeRates,
+    PairFeeRates(AssetPairKey),        // pair -> fee rates overriding FeeRates
+    FeeTiers,                          // (min volume, bps) ascending by volume
+    UserVolume(Address),               // user -> settled quote volume this epoch (persistent)
+    FeeManager,
+    FeeRecipient,
+    FeesCollected(Address),            // asset -> lifetime fees charged
+    FeeBalance(Address),               // asset -> accrued protocol fees not yet paid out
+    FeesClaimed(Address),              // asset -> lifetime fees paid out of every fee bucket
+    DailyFees(u64),                    // day -> asset -> fees charged that day (persistent)
+    KeeperReward(Address),             // asset -> reward per settled batch item, paid from fees
+    BatchSummaryOnly,                  // settle_trades emits only its BatchSettledEvent
+    AutoPauseOnDeficit,                // reconcile pauses an asset the vault is short in
+    MaxFeeBps,
+    MaxFeeRatioBps,                    // cap on a settlement's total fee, in bps of its notional
+    MakerRebateBps,
+    FeeSplit,
+    InsuranceAdmin,
+    InsuranceBalance(Address),         // asset -> accrued insurance fund share of fees
+    Referrer(Address),                 // user -> address that referred them (persistent)
+    ReferrerBalance(BalanceDataKey),   // (referrer, asset) -> accrued referral share of fees (persistent)
+    ReferralTotal(Address),            // asset -> sum of all referrer balances
+    NetFlows(Address),                 // user -> asset -> deposits - withdrawals + settlement deltas (persistent)
+    EngineAllowance(BalanceDataKey),   // (user, asset) -> amount the engine may still settle (persistent)
+    DepositCap(Address),               // asset -> largest vault balance a deposit may reach
+    UserDepositCap(BalanceDataKey),    // (user, asset) -> cap overriding DepositCap (persistent)
+}
+
+// Per-user vault activity keys (persistent). DataKey is near the 50-variant
+// limit for contract types; since a key encodes only its variant name, names
+// here must not repeat a DataKey variant
+#[derive(Clone)]
+#[contracttype]
+pub enum UserDataKey {
+    DepositHistory(Address),           // user -> last VAULT_HISTORY_LENGTH deposits, oldest first
+    WithdrawHistory(Address),          // user -> last VAULT_HISTORY_LENGTH withdrawals, oldest first
+    ActivityHead(Address),             // user -> newest activity journal entries, oldest first
+    ActivityPage(Address, u64),        // (user, page) -> ACTIVITY_PAGE_SIZE older journal entries, oldest first
+    VolumeTotals(Address),             // user -> (lifetime volume, epoch start, epoch volume)
+    TradeHistoryHead(Address),         // user -> (archived pages, newest (trade id, side taken) entries, oldest first)
+    TradeHistoryPage(Address, u32),    // (user, page) -> ACTIVITY_PAGE_SIZE older (trade id, side taken) entries, oldest first
+}
+
+// Activity journal (and trade history) entries per storage page, so a busy
+// account never grows a single ledger entry without bound
+pub(crate) const ACTIVITY_PAGE_SIZE: u64 = 32;
+
+// Deposits (and withdrawals) kept per user, newest last; older movements
+// drop off the front and remain only in the paged activity journal
+pub(crate) const VAULT_HISTORY_LENGTH: u32 = 50;
+
+// Venue-level market data keys (instance unless noted). Like UserDataKey,
+// names here must not repeat a DataKey or UserDataKey variant
+#[derive(Clone)]
+#[contracttype]
+pub enum MarketDataKey {
+    GlobalStats,                       // lifetime trade count, volumes and last settlement time
+    DailyVolume(AssetPairKey, u64),    // (canonical pair, day) -> quote volume and trade count (persistent)
+    HourlyVolume(AssetPairKey, u64),   // (canonical pair, hour) -> (base, quote) amounts settled (persistent)
+    VwapMinVolume,                     // base volume a get_vwap window needs to report a price
+    PairStats(AssetPairKey),           // canonical pair -> lifetime trade count, volumes and last trade time
+    PairLargestTrade(AssetPairKey),    // canonical pair -> its largest settlement by quote notional (persistent)
+    LastPrice(AssetPairKey),           // canonical pair -> latest execution price, canonical quote per base
+    LargestTrade,                      // largest settlement by quote notional in any pair (persistent)
+    MakerRebates(Address),             // asset -> lifetime maker rebates paid out of taker fees
+    DaySettlementPage(u64, u32),       // (day, page) -> ACTIVITY_PAGE_SIZE older (sequence, timestamp) entries (persistent)
+}
+
+// Balance lock keys added once DataKey was full (persistent, unlike the
+// instance balances they restrict). Names here must not repeat a variant of
+// the other key enums
+#[derive(Clone)]
+#[contracttype]
+pub enum VaultDataKey {
+    LockedBalance(BalanceDataKey),     // (user, asset) -> part of the balance that cannot be withdrawn (persistent)
+    Lock(BytesN<32>),                  // lock id -> BalanceLock the user may reclaim after it expires (persistent)
+    LockIds(BalanceDataKey),           // (user, asset) -> ids of its unreclaimed BalanceLocks (persistent)
+}
+
+// Deferred settlement keys. Names here must not repeat a variant of the
+// other key enums
+#[derive(Clone)]
+#[contracttype]
+pub enum QueueDataKey {
+    SettlementQueue,                   // (head, tail) positions of the queued instructions (instance)
+    QueuedSettlement(u64),             // position -> QueuedInstruction awaiting process_queue (persistent)
+}
+
+// Settings added once DataKey was full (instance). Names here must not
+// repeat a variant of the other key enums
+#[derive(Clone)]
+#[contracttype]
+pub enum ConfigDataKey {
+    RoundingMode,                      // rounding of fees computed from on-chain rates
+    ReferralBps,                       // share of quote fees rebated to a trade's referrer
+    VolumeEpochLength,                 // seconds per get_user_volume_stats epoch
+    AuthMode,                          // who must authorize settle_trade
 }
@@ -0,0 +1,81 @@
+#![cfg(test)]
+
+//! Event helpers shared by the tests. `env.events().all()` only holds the
+//! events of the last contract invocation, so check events straight after
+//! the call that published them
+
+use crate::events::SettlementEvent;
+use crate::types::SettledTrade;
+use soroban_sdk::{testutils::Events as _, Address, Env, Event as _, IntoVal, Map, Symbol, Val, Vec};
+
+/// Topics of the last event published by any contract
+pub fn last_event_topics(env: &Env) -> Vec<Symbol> {
+    let (_, topics, _) = env.events().all().last().expect("no event");
+    Vec::from_iter(env, topics.iter().map(|topic| -> Symbol { topic.into_val(env) }))
+}
+
+/// Topics and data map of the last event this contract published
+pub fn last_contract_event(env: &Env, contract_id: &Address) -> (Vec<Symbol>, Map<Symbol, Val>) {
+    let (_, topics, data) = env
+        .events()
+        .all()
+        .iter()
+        .filter(|(id, _, _)| id == contract_id)
+        .last()
+        .expect("no contract event");
+    let topics = Vec::from_iter(env, topics.iter().map(|topic| -> Symbol { topic.into_val(env) }));
+    (topics, data.into_val(env))
+}
+
+/// Read a field from the data map of the last event this contract published
+pub fn last_event_field(env: &Env, contract_id: &Address, field: &str) -> i128 {
+    let (_, data) = last_contract_event(env, contract_id);
+    data.get(Symbol::new(env, field)).unwrap().into_val(env)
+}
+
+/// Assert the last SettlementEvent published matches `expected` in every field
+pub fn assert_settlement_event(env: &Env, expected: &SettlementEvent) {
+    let topics = expected.topics(env);
+    let (_, _, data) = env
+        .events()
+        .all()
+        .iter()
+        .filter(|(_, event_topics, _)| *event_topics == topics)
+        .last()
+        .expect("no settlement event");
+    let data: Map<Symbol, Val> = data.into_val(env);
+    let expected: Map<Symbol, Val> = expected.data(env).into_val(env);
+    assert_eq!(data, expected);
+}
+
+/// The SettlementEvent a successful settlement should publish, built from the
+/// outcome settle_trade returned. Tests override fields they check directly
+pub fn expected_settlement_event(settled: &SettledTrade, fee_recipient: &Address) -> SettlementEvent {
+    let record = &settled.record;
+    SettlementEvent {
+        schema_version: crate::events::SETTLEMENT_EVENT_VERSION,
+        trade_id: record.trade_id.clone(),
+        buy_user: record.buy_user.clone(),
+        sell_user: record.sell_user.clone(),
+        base_asset: record.base_asset.clone(),
+        quote_asset: record.quote_asset.clone(),
+        base_amount: record.base_amount,
+        quote_amount: record.quote_amount,
+        execution_price: record.execution_price,
+        execution_quantity: record.execution_quantity,
+        fee_base: record.fee_base,
+        fee_quote: record.fee_quote,
+        maker_rebate: record.maker_rebate,
+        fee_asset: record.fee_asset.clone(),
+        fee_amount: record.fee_amount,
+        referrer: record.referrer.clone(),
+        fee_recipient: fee_recipient.clone(),
+        taker_side: record.taker_side,
+        timestamp: record.timestamp,
+        sequence: record.sequence,
+        buyer_base_after: settled.buyer_base_balance,
+        buyer_quote_after: settled.buyer_quote_balance,
+        seller_base_after: settled.seller_base_balance,
+        seller_quote_after: settled.seller_quote_balance,
+    }
+}
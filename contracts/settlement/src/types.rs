@@ -1,4 +1,4 @@
-use soroban_sdk::{contracttype, Address, BytesN, String as SorobanString};
+use soroban_sdk::{contracterror, contracttype, Address, Bytes, BytesN, Map, String as SorobanString, Vec};
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -17,9 +17,115 @@ pub struct SettlementInstruction {
     pub quote_asset: Address, // Stellar asset contract address
     pub base_amount: i128,
     pub quote_amount: i128,
-    pub fee_base: i128,
-    pub fee_quote: i128,
+    pub fee_base: i128,  // Ignored once an on-chain fee rate is configured; negative pays a maker seller
+    pub fee_quote: i128, // Ignored once an on-chain fee rate is configured; negative pays a maker buyer
     pub timestamp: u64,
+    pub min_price: i128, // Lowest acceptable execution price (0 = unbounded)
+    pub max_price: i128, // Highest acceptable execution price (0 = unbounded)
+    pub buy_limit_price: i128,  // Buy order's limit: highest price it pays (0 = no limit)
+    pub sell_limit_price: i128, // Sell order's limit: lowest price it accepts (0 = no limit)
+    pub memo: Bytes,            // Optional free-form memo (empty if unused)
+    pub proof: Vec<BytesN<32>>, // Optional matching proof hashes (empty if unused)
+    pub taker_side: Side,       // Side of the incoming order that took liquidity
+    pub expiry: u64,            // Latest ledger timestamp it may settle at (0 = never expires)
+    pub fee_asset: Option<Address>, // Fee token charged instead of base/quote fees (None = base/quote)
+    pub fee_amount: i128,           // Taker's fee in fee_asset (ignored while fee_asset is None)
+    pub referrer: Option<Address>,  // Referrer the trade is attributed to (None = unattributed)
+}
+
+/// On-chain fee rates in basis points for the maker and taker sides
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeRates {
+    pub maker_bps: u32,
+    pub taker_bps: u32,
+}
+
+/// A user's settled volume within the current fee-tier epoch
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserVolume {
+    pub epoch: u64,
+    pub volume: i128,
+}
+
+/// A user's settled quote volume for external rewards programs, kept apart
+/// from the fee-tier volume
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserVolumeStats {
+    pub lifetime_quote_volume: i128,
+    pub current_epoch_volume: i128, // Volume since the current epoch began
+    pub epoch_index: u64,           // Ledger timestamp / epoch length
+}
+
+/// Shares of each settlement fee, in basis points of the fee, paid to the
+/// insurance fund and to the taker's referrer; the protocol keeps the rest
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FeeSplit {
+    pub insurance_bps: u32,
+    pub referrer_bps: u32,
+}
+
+/// Upper bounds for the variable-length instruction fields, checked before
+/// settle_trade touches any other state
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InstructionLimits {
+    pub max_memo_bytes: u32,
+    pub max_proof_len: u32,
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// How settlement delivers each side's proceeds
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SettlementMode {
+    Internal,          // Credit proceeds to vault balances (default)
+    ImmediateTransfer, // Transfer proceeds straight to counterparties' wallets
+}
+
+/// Who must authorize settle_trade and settle_trade_priced
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuthMode {
+    EngineOnly,   // The matching engine (default)
+    BothParties,  // Buyer and seller, as in settle_p2p
+    EngineOrBoth, // The matching engine, or both counterparties while no engine is set
+}
+
+/// Which assets settlement fees are charged in
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FeeMode {
+    PerLeg,    // Seller pays in the base asset, buyer in the quote asset (default)
+    QuoteOnly, // Both sides pay in the quote asset; the seller's fee comes out of its proceeds
+}
+
+/// How on-chain fee rates round a fee with a fractional remainder
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RoundingMode {
+    Floor,  // Round down (default)
+    Ceil,   // Round up whenever there is a remainder
+    HalfUp, // Round to nearest, half a unit up
+}
+
+/// Typed errors that abort a call and revert everything it did: settlement
+/// failures a returned SettlementResult cannot undo, and rejected queries
+#[contracterror]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum SettlementError {
+    TransferFailed = 1,  // An immediate-transfer leg failed after the other paid out
+    TooManyTradeIds = 2, // A lookup named more than MAX_SETTLEMENT_LOOKUP trade IDs
 }
 
 #[contracttype]
@@ -30,6 +136,19 @@ pub enum SettlementResult {
     InvalidMatchingProof,
     InsufficientBalance,
     TransferFailed,
+    PriceOutOfBounds,
+    AssetPaused,
+    InstructionTooLarge,
+    FeeTooHigh,
+    AllowanceExceeded,
+    Expired,
+    BaseFeeNotAllowed,
+    InsufficientProtocolBalance,
+    InconsistentExecution,
+    DuplicateTrade,
+    ExcessiveFee,
+    SelfTrade,
+    NegativeTakerFee,
 }
 
 #[contracttype]
@@ -44,5 +163,347 @@ pub struct SettlementRecord {
     pub quote_amount: i128,
     pub execution_price: i128,
     pub execution_quantity: i128,
+    pub fee_base: i128,  // Seller's fee in per-leg mode, after any on-chain computation (negative for a maker rebate)
+    pub fee_quote: i128, // Buyer's fee, plus the seller's in quote-only mode (negative for a maker rebate)
+    pub maker_rebate: i128, // Paid to the maker out of the taker's fee, in the taker fee's asset
+    pub fee_asset: Option<Address>, // Fee token the taker paid fee_amount in, if not base/quote
+    pub fee_amount: i128,
+    pub referrer: Option<Address>, // Referrer the trade was attributed to, if any
+    pub taker_side: Side,
+    pub timestamp: u64,
+    pub sequence: u64, // Global settlement order, starting at 1
+}
+
+/// One deposit into or withdrawal from a user's vault balance
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VaultMovement {
+    pub asset: Address,
+    pub amount: i128, // Amount credited or debited
+    pub timestamp: u64,
+}
+
+/// What moved a user's vault balance, as recorded in their activity journal
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ActivityKind {
+    Deposit,
+    Withdrawal,
+    Import,         // Balance migrated in by import_balances
+    Buy,            // A settlement the user bought in, one entry per asset it moved
+    Sell,           // A settlement the user sold in, one entry per asset it moved
+    ReferralRebate, // Share of a referred trade's fees
+    KeeperReward,   // Reward for settling a batch
+}
+
+/// One change to a user's vault balance, in the order it happened
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JournalEntry {
+    pub kind: ActivityKind,
+    pub asset: Address,
+    pub amount: i128,                   // Signed change to the vault balance
+    pub trade_id: Option<BytesN<32>>,   // Settlement behind a trade or rebate entry
+    pub timestamp: u64,
+    pub sequence: u64,                  // Position in the user's journal, starting at 0
+}
+
+/// One page of a user's activity journal, oldest first. `next_cursor` is
+/// the sequence of the next entry, or None once the newest has been returned
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ActivityPage {
+    pub entries: Vec<JournalEntry>,
+    pub next_cursor: Option<u64>,
+}
+
+/// Compact view of a settlement from one participant's perspective
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TradeSummary {
+    pub trade_id: BytesN<32>,
+    pub side: Side, // Side taken by the queried user (the taker, in the global feed)
+    pub price: i128,
+    pub base_amount: i128,
+    pub timestamp: u64,
+}
+
+/// One page of a user's trade history, newest first. `next_cursor` resumes
+/// just past the last record, or is None once the oldest has been returned
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TradeHistoryPage {
+    pub records: Vec<SettlementRecord>,
+    pub next_cursor: Option<u64>, // History index of the last record
+}
+
+/// One page of the global settlement feed, newest first, paged like
+/// TradeHistoryPage
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TradeSummaryPage {
+    pub summaries: Vec<TradeSummary>,
+    pub next_cursor: Option<u64>, // Global sequence of the last summary
+}
+
+/// What the vault owes in an asset against the tokens it actually holds
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssetHealth {
+    pub asset: Address,
+    pub liability: i128,     // Vault balances plus unclaimed fee, insurance and referral balances
+    pub vault_balance: i128, // Token balance held by the contract
+}
+
+/// A user's vault balance in one asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssetBalance {
+    pub asset: Address,
+    pub amount: i128, // Vault balance, including any locked part
+    pub locked: i128, // Part of amount locked behind resting orders, which cannot be withdrawn
+}
+
+/// A user's vault balance in one asset, split into what may be withdrawn
+/// and what is locked behind resting orders
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BalanceDetail {
+    pub free: i128,   // total - locked, never negative
+    pub locked: i128, // Part of the balance that cannot be withdrawn
+    pub total: i128,  // Vault balance, as get_balance reports it
+}
+
+/// An instruction waiting for process_queue, with the matching engine
+/// that enqueued it
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueuedInstruction {
+    pub instruction: SettlementInstruction,
+    pub engine: Address, // Must still be the matching engine when it settles
+}
+
+/// A lock placed with lock_balance_until, counted in the user's locked
+/// balance until it is reclaimed with unlock_expired
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BalanceLock {
+    pub user: Address,
+    pub asset: Address,
+    pub amount: i128,    // Amount locked when the lock was placed
+    pub remaining: i128, // Part of it settlements and unlocks have not drawn on yet
+    pub expires_at: u64, // Ledger timestamp from which anyone may reclaim it
+}
+
+/// Contract settings in one view, for settings pages and monitoring
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ContractConfig {
+    pub interface_version: u32,
+    pub admin: Address,
+    pub matching_engine: Option<Address>,
+    pub fee_recipient: Address,
+    pub asset_a: Address,
+    pub asset_b: Address,
+    pub asset_a_paused: bool,
+    pub asset_b_paused: bool,
+    pub settlement_mode: SettlementMode,
+    pub fee_mode: FeeMode,
+    pub maker_fee_bps: Option<u32>, // Global on-chain rate, None while fees are instruction-supplied
+    pub taker_fee_bps: Option<u32>, // Pair overrides are read with get_effective_fee
+    pub max_fee_bps: Option<u32>,
+    pub maker_rebate_bps: u32,
+}
+
+/// The venue-wide fee settings, reported before and after each change by
+/// FeeParamsChangedEvent. Pair overrides, tiers and keeper rewards have
+/// their own events
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeParams {
+    pub fee_mode: FeeMode,
+    pub rounding_mode: RoundingMode,
+    pub maker_fee_bps: Option<u32>, // None while fees are instruction-supplied
+    pub taker_fee_bps: Option<u32>,
+    pub insurance_bps: u32,
+    pub referrer_bps: u32,
+    pub referral_bps: u32,
+    pub maker_rebate_bps: u32,
+    pub max_fee_bps: Option<u32>,
+    pub max_fee_ratio_bps: Option<u32>,
+}
+
+/// Lifetime fees in an asset and how much of them has been paid out
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeTotals {
+    pub collected: i128, // Charged on settlements, before maker rebates
+    pub claimed: i128,   // Paid out by claims and insurance payouts
+    pub unclaimed: i128, // Still held in the fee, insurance and referral balances
+}
+
+/// Vault balances in an asset next to the segregated protocol balances
+/// Together they add up to what the vault owes in the asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VaultTotals {
+    pub asset: Address,
+    pub user_balances: i128,     // Sum of all vault balances, the admin's included
+    pub fee_balance: i128,       // Unclaimed protocol fees
+    pub insurance_balance: i128, // Unclaimed insurance fund
+    pub referral_balance: i128,  // Unclaimed referral fees across all referrers
+}
+
+/// Result of reconciling an asset's token holdings against what the vault owes
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReconciliationReport {
+    pub asset: Address,
+    pub token_balance: i128,  // Token balance held by the contract
+    pub internal_total: i128, // Vault balances plus unclaimed fee, insurance and referral balances
+    pub surplus: i128,        // token_balance - internal_total; negative is a deficit
+    pub paused: bool,         // Whether the asset is paused after reconciling
+}
+
+/// Snapshot for monitoring: pause state, ledger time and per-asset solvency
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthStatus {
+    pub paused: bool, // Any supported asset is paused
     pub timestamp: u64,
+    pub assets: Vec<AssetHealth>,
+}
+
+/// What settle_trade did with an instruction: its result code and, where
+/// there is more to say, the details behind it
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SettlementOutcome {
+    pub result: SettlementResult,
+    pub detail: OutcomeDetail,
+}
+
+impl SettlementOutcome {
+    /// A rejection the result code fully describes
+    pub fn rejected(result: SettlementResult) -> Self {
+        SettlementOutcome { result, detail: OutcomeDetail::None }
+    }
+
+    /// A rejection for want of `required` of `asset` where `user` had only `available`
+    pub fn shortfall(result: SettlementResult, user: &Address, asset: &Address, required: i128, available: i128) -> Self {
+        SettlementOutcome {
+            result,
+            detail: OutcomeDetail::Shortfall(Shortfall {
+                user: user.clone(),
+                asset: asset.clone(),
+                required,
+                available,
+            }),
+        }
+    }
+}
+
+/// What a settlement outcome carries beyond its result code
+// Host object handles are a few bytes on-chain; only native test builds,
+// where each one carries its Env, make these variants large
+#[allow(clippy::large_enum_variant)]
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OutcomeDetail {
+    None,
+    Settled(SettledTrade),
+    Shortfall(Shortfall),
+}
+
+/// A successful settlement's record and both sides' vault balances after it
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SettledTrade {
+    pub record: SettlementRecord,
+    pub buyer_base_balance: i128,
+    pub buyer_quote_balance: i128,
+    pub seller_base_balance: i128,
+    pub seller_quote_balance: i128,
+}
+
+/// What a rejected settlement lacked: a user's vault balance or engine
+/// allowance, or (with the contract as `user`) the protocol fee balance
+/// needed for a maker rebate
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Shortfall {
+    pub user: Address,
+    pub asset: Address,
+    pub required: i128,
+    pub available: i128,
+}
+
+/// What settle_trade would do with an instruction, from preview_settle
+/// On any result but Success the amounts are all zero
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SettlementPreview {
+    pub result: SettlementResult,
+    pub execution_price: i128,
+    pub fee_base: i128,
+    pub fee_quote: i128,
+    pub maker_rebate: i128,
+    pub buyer_base_balance: i128, // Vault balances after the trade
+    pub buyer_quote_balance: i128,
+    pub seller_base_balance: i128,
+    pub seller_quote_balance: i128,
+}
+
+/// Settled volume in one pair on one day (timestamp / 86_400)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DailyVolume {
+    pub day: u64,
+    pub quote_volume: i128, // In the pair's canonical quote asset
+    pub trade_count: u32,
+}
+
+/// Venue-level lifetime trading totals
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GlobalStats {
+    pub trade_count: u64,                   // Successful settlements
+    pub base_volume: Map<Address, i128>,    // asset -> amount settled with it as the base
+    pub quote_volume: Map<Address, i128>,   // asset -> amount settled with it as the quote
+    pub fees_collected: Map<Address, i128>, // supported asset -> lifetime fees charged, before maker rebates (get_fees_collected)
+    pub last_settlement: u64,               // Ledger timestamp of the latest settlement, 0 if none
+}
+
+/// Lifetime trading totals for one pair, in its canonical orientation
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PairStats {
+    pub trade_count: u64,  // Successful settlements in the pair
+    pub base_volume: i128, // Canonical base asset settled
+    pub quote_volume: i128, // Canonical quote asset settled
+    pub last_trade: u64,   // Record timestamp of the pair's latest settlement, 0 if none
+}
+
+/// The largest settlement by quote notional, copied out of its record so
+/// it outlives the record
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LargestTrade {
+    pub trade_id: BytesN<32>,
+    pub base_asset: Address,  // Canonical base of the trade's pair
+    pub quote_asset: Address, // Canonical quote of the trade's pair
+    pub base_amount: i128,
+    pub quote_amount: i128,   // Quote notional trades are ranked by
+    pub timestamp: u64,       // Record timestamp
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorageStats {
+    pub users_with_balance: u32, // Users holding a nonzero balance in any asset
+    pub settlement_count: u64,   // Settlement records stored
+    pub latest_sequence: u64,    // Most recent global settlement sequence
+    pub largest_history: u32,    // Length of the longest user trade history
+    pub instance_entries: u32,   // Per-user/per-trade keys kept in instance storage
+    pub persistent_entries: u32, // Per-user/per-trade keys kept in persistent storage
 }
@@ -141,6 +141,7 @@ class MatchingEngine:
                 fee_base=0,
                 fee_quote=0,
                 timestamp=int(time.time()),
+                taker_side=trade.taker_side,
                 buy_order_signature="",  # Not needed for settlement authorization
                 sell_order_signature=""   # Not needed for settlement authorization
             )
@@ -44,7 +44,7 @@ class OrderBook:
                     trade_quantity = min(remaining_quantity, sell_order.quantity - sell_order.filled_quantity)
                     
                     if trade_quantity > 0:
-                        trade = self._create_trade(order, sell_order, best_price, trade_quantity)
+                        trade = self._create_trade(order, sell_order, best_price, trade_quantity, OrderSide.Buy)
                         trades.append(trade)
                         
                         remaining_quantity -= trade_quantity
@@ -81,7 +81,7 @@ class OrderBook:
                     trade_quantity = min(remaining_quantity, buy_order.quantity - buy_order.filled_quantity)
                     
                     if trade_quantity > 0:
-                        trade = self._create_trade(buy_order, order, best_price, trade_quantity)
+                        trade = self._create_trade(buy_order, order, best_price, trade_quantity, OrderSide.Sell)
                         trades.append(trade)
                         
                         remaining_quantity -= trade_quantity
@@ -110,7 +110,7 @@ class OrderBook:
 
         return trades
 
-    def _create_trade(self, buy_order: Order, sell_order: Order, price: Decimal, quantity: Decimal) -> Trade:
+    def _create_trade(self, buy_order: Order, sell_order: Order, price: Decimal, quantity: Decimal, taker_side: OrderSide) -> Trade:
         trade_id = str(uuid.uuid4())
         timestamp = int(time.time())
         
@@ -123,7 +123,8 @@ class OrderBook:
             buy_user=buy_order.user_address,
             sell_user=sell_order.user_address,
             asset_pair=self.asset_pair,
-            timestamp=timestamp
+            timestamp=timestamp,
+            taker_side=taker_side
         )
 
     def _add_order_to_book(self, order: Order):
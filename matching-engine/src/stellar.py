@@ -403,12 +403,29 @@ class StellarService:
         data = {
             scval.to_symbol("base_amount"): self._to_i128(instruction.base_amount),
             scval.to_symbol("base_asset"): scval.to_address(base_asset_addr),
+            scval.to_symbol("buy_limit_price"): self._to_i128(instruction.buy_limit_price),
             scval.to_symbol("buy_user"): scval.to_address(instruction.buy_user),
+            scval.to_symbol("expiry"): scval.to_uint64(instruction.expiry),
+            scval.to_symbol("fee_amount"): self._to_i128(instruction.fee_amount),
+            scval.to_symbol("fee_asset"): (
+                scval.to_address(self.get_contract_address(instruction.fee_asset))
+                if instruction.fee_asset
+                else scval.to_void()
+            ),
             scval.to_symbol("fee_base"): self._to_i128(instruction.fee_base),
             scval.to_symbol("fee_quote"): self._to_i128(instruction.fee_quote),
+            scval.to_symbol("max_price"): self._to_i128(instruction.max_price),
+            scval.to_symbol("memo"): scval.to_bytes(instruction.memo),
+            scval.to_symbol("min_price"): self._to_i128(instruction.min_price),
+            scval.to_symbol("proof"): scval.to_vec([scval.to_bytes(p) for p in instruction.proof]),
             scval.to_symbol("quote_amount"): self._to_i128(instruction.quote_amount),
             scval.to_symbol("quote_asset"): scval.to_address(quote_asset_addr),
+            scval.to_symbol("referrer"): (
+                scval.to_address(instruction.referrer) if instruction.referrer else scval.to_void()
+            ),
+            scval.to_symbol("sell_limit_price"): self._to_i128(instruction.sell_limit_price),
             scval.to_symbol("sell_user"): scval.to_address(instruction.sell_user),
+            scval.to_symbol("taker_side"): scval.to_vec([scval.to_symbol(instruction.taker_side.value)]),
             scval.to_symbol("timestamp"): scval.to_uint64(instruction.timestamp),
             scval.to_symbol("trade_id"): scval.to_bytes(trade_id_bytes),
         }
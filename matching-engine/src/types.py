@@ -55,6 +55,7 @@ class Trade(BaseModel):
     sell_user: str
     asset_pair: AssetPair
     timestamp: int
+    taker_side: OrderSide = OrderSide.Buy  # Side of the incoming (matched) order
 
 class SettlementInstruction(BaseModel):
     trade_id: str
@@ -67,6 +68,17 @@ class SettlementInstruction(BaseModel):
     fee_base: int = 0
     fee_quote: int = 0
     timestamp: int
+    min_price: int = 0  # 0 = unbounded
+    max_price: int = 0  # 0 = unbounded
+    buy_limit_price: int = 0  # Buy order's limit price (0 = no limit)
+    sell_limit_price: int = 0  # Sell order's limit price (0 = no limit)
+    memo: bytes = b""  # Bounded by the contract's max_memo_bytes
+    proof: List[bytes] = []  # 32-byte hashes, bounded by max_proof_len
+    taker_side: OrderSide = OrderSide.Buy
+    expiry: int = 0  # Latest ledger timestamp it may settle at (0 = never expires)
+    fee_asset: Optional[str] = None  # Separate fee token the taker pays instead of base/quote fees
+    fee_amount: int = 0  # Taker's fee in fee_asset
+    referrer: Optional[str] = None  # Referrer the trade is attributed to
 
 class PriceLevel(BaseModel):
     price: Decimal